@@ -6,6 +6,8 @@ pub trait BuilderExt {
     fn with_derive_builder_into(self, path: &str, attr: &[&str]) -> Self;
     fn with_derive_builder_option(self, path: &str, attr: &[&str]) -> Self;
     fn with_serde(self, path: &[&str]) -> Self;
+    fn with_serde_default(self, path: &[&str]) -> Self;
+    fn with_serde_enum(self, fields: &[(&str, &str)]) -> Self;
 }
 
 impl BuilderExt for tonic_build::Builder {
@@ -46,10 +48,42 @@ impl BuilderExt for tonic_build::Builder {
             acc.type_attribute(path, "#[derive(serde::Serialize, serde::Deserialize)]")
         })
     }
+
+    // missing fields of a json message take their protobuf default
+    fn with_serde_default(self, path: &[&str]) -> Self {
+        path.iter().fold(self, |acc, path| {
+            acc.message_attribute(path, "#[serde(default)]")
+        })
+    }
+
+    // enumerations are written by name, see src/pb/serde_enum.rs
+    fn with_serde_enum(self, fields: &[(&str, &str)]) -> Self {
+        fields.iter().fold(self, |acc, (field, module)| {
+            acc.field_attribute(
+                field,
+                format!("#[serde(with = \"crate::pb::serde_enum::{module}\")]"),
+            )
+        })
+    }
 }
 fn main() {
+    // messages of the http api
+    let json_messages = [
+        ".service_registry.ServiceInstance",
+        ".service_registry.HealthCheck",
+        ".service_registry.ServiceEvent",
+        ".service_registry.OperationStatus",
+    ];
     tonic_build::configure()
         .out_dir("src/pb")
+        .with_serde(&json_messages)
+        .with_serde_default(&json_messages)
+        .with_serde_enum(&[
+            (".service_registry.ServiceInstance.scheme", "scheme"),
+            (".service_registry.ServiceInstance.status", "service_status"),
+            (".service_registry.HealthCheck.scheme", "scheme"),
+            (".service_registry.ServiceEvent.kind", "event_kind"),
+        ])
         .protoc_arg("--experimental_allow_proto3_optional")
         .compile(
             &["protos/hub.proto", "protos/health_check.proto"],
@@ -38,6 +38,7 @@ async fn main() {
             }),
             status: 0,
             scheme: Scheme::Http as i32,
+            namespace: "".to_string(),
         })
         .await
         .unwrap();
@@ -45,6 +46,7 @@ async fn main() {
         let mut stream = client
             .subscribe(SubscribeRequest {
                 service: "test".to_string(),
+                ..Default::default()
             })
             .await
             .unwrap()
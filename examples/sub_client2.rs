@@ -38,6 +38,7 @@ async fn main() {
             }) */None,
             status: 0,
             scheme: Scheme::Http as i32,
+            namespace: "".to_string(),
         })
         .await
         .unwrap();
@@ -46,6 +47,7 @@ async fn main() {
         let mut stream = client
             .subscribe_to_service(SubscribeRequest {
                 service: "ws".to_string(),
+                ..Default::default()
             })
             .await
             .unwrap()
@@ -0,0 +1,370 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hickory_server::authority::MessageResponseBuilder;
+use hickory_server::proto::op::{Header, MessageType, OpCode, ResponseCode};
+use hickory_server::proto::rr::rdata::{A, AAAA, SRV};
+use hickory_server::proto::rr::{Name, RData, Record, RecordType};
+use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+use hickory_server::ServerFuture;
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{debug, error};
+
+use crate::service::client::WEIGHT_METADATA;
+use crate::service::hub::{Hub, ServiceKey};
+use crate::service::{ServiceInstance, ServiceStatus};
+
+/// the top level domain answered by synapse, queries for other names are
+/// refused
+pub const DOMAIN: &str = "synapse";
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// a parsed query name:
+///
+/// - `[<tag>.]<service>.service[.<namespace>].synapse` for the instances of a
+///   service, with the tag when given
+/// - `<id>.<service>.instance[.<namespace>].synapse` for one instance, the
+///   target of the SRV records
+///
+/// the names of the services and namespaces are normalized, the tags and the
+/// instance ids are kept as they are.
+#[derive(Debug, PartialEq)]
+enum Lookup {
+    Service {
+        key: ServiceKey,
+        tag: Option<String>,
+    },
+    Instance {
+        key: ServiceKey,
+        id: String,
+    },
+}
+
+impl Lookup {
+    /// whether the name is in the zone of synapse
+    fn in_zone(name: &str) -> bool {
+        name.trim_end_matches('.')
+            .rsplit('.')
+            .next()
+            .is_some_and(|domain| domain.eq_ignore_ascii_case(DOMAIN))
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        let mut labels: Vec<&str> = name.trim_end_matches('.').split('.').collect();
+        if !labels.pop()?.eq_ignore_ascii_case(DOMAIN) {
+            return None;
+        }
+        let kind = |label: &str| label.to_ascii_lowercase();
+        let namespace = match labels.last().map(|label| kind(label)).as_deref() {
+            Some("service") | Some("instance") => "",
+            _ => labels.pop()?,
+        };
+        match (kind(labels.pop()?).as_str(), labels.as_slice()) {
+            ("service", [service]) => Some(Self::Service {
+                key: ServiceKey::new(namespace, *service),
+                tag: None,
+            }),
+            ("service", [tag, service]) => Some(Self::Service {
+                key: ServiceKey::new(namespace, *service),
+                tag: Some(tag.to_string()),
+            }),
+            ("instance", [id, service]) => Some(Self::Instance {
+                key: ServiceKey::new(namespace, *service),
+                id: id.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// answers A, AAAA and SRV queries with the healthy instances of the registry
+#[derive(Debug, Clone)]
+pub struct DnsHandler {
+    hub: Hub,
+    ttl: u32,
+}
+
+impl DnsHandler {
+    pub fn new(hub: Hub, ttl: u32) -> Self {
+        Self { hub, ttl }
+    }
+
+    /// the answers and the additional records, or the error code
+    fn lookup(
+        &self,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<(Vec<Record>, Vec<Record>), ResponseCode> {
+        let name_ascii = name.to_ascii();
+        if !Lookup::in_zone(&name_ascii) {
+            return Err(ResponseCode::Refused);
+        }
+        let lookup = Lookup::parse(&name_ascii).ok_or(ResponseCode::NXDomain)?;
+        let (key, tag, id) = match lookup {
+            Lookup::Service { key, tag } => (key, tag, None),
+            Lookup::Instance { key, id } => (key, None, Some(id)),
+        };
+        let mut instances: Vec<_> = self
+            .hub
+            .instances(&key)
+            .into_iter()
+            .filter(|instance| {
+                instance.status() == ServiceStatus::Up
+                    && tag.as_ref().is_none_or(|tag| instance.tags.contains(tag))
+                    && id.as_ref().is_none_or(|id| &instance.id == id)
+            })
+            .collect();
+        if instances.is_empty() {
+            return Err(ResponseCode::NXDomain);
+        }
+        instances.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for instance in &instances {
+            match record_type {
+                RecordType::A | RecordType::AAAA => {
+                    answers.extend(self.address(name.clone(), instance, record_type));
+                }
+                RecordType::SRV => {
+                    let Some(target) = self.target(&key, instance) else {
+                        continue;
+                    };
+                    let weight = instance
+                        .metadata
+                        .get(WEIGHT_METADATA)
+                        .and_then(|weight| weight.parse().ok())
+                        .unwrap_or(1);
+                    let srv = SRV::new(1, weight, instance.port as u16, target.clone());
+                    answers.push(Record::from_rdata(name.clone(), self.ttl, RData::SRV(srv)));
+                    additionals.extend(self.address(target.clone(), instance, RecordType::A));
+                    additionals.extend(self.address(target, instance, RecordType::AAAA));
+                }
+                _ => {}
+            }
+        }
+        Ok((answers, additionals))
+    }
+
+    fn address(
+        &self,
+        name: Name,
+        instance: &ServiceInstance,
+        record_type: RecordType,
+    ) -> Option<Record> {
+        let rdata = match (instance.address.parse::<IpAddr>().ok()?, record_type) {
+            (IpAddr::V4(ip), RecordType::A) => RData::A(A(ip)),
+            (IpAddr::V6(ip), RecordType::AAAA) => RData::AAAA(AAAA(ip)),
+            _ => return None,
+        };
+        Some(Record::from_rdata(name, self.ttl, rdata))
+    }
+
+    /// the name of the instance, or its host name when it is not an ip address
+    fn target(&self, key: &ServiceKey, instance: &ServiceInstance) -> Option<Name> {
+        if instance.address.parse::<IpAddr>().is_err() {
+            return Name::from_ascii(format!("{}.", instance.address)).ok();
+        }
+        Name::from_ascii(format!(
+            "{}.{}.instance.{}.{}.",
+            instance.id, key.name, key.namespace, DOMAIN
+        ))
+        .ok()
+    }
+}
+
+#[async_trait]
+impl RequestHandler for DnsHandler {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        let builder = MessageResponseBuilder::from_message_request(request);
+        let result =
+            if request.op_code() != OpCode::Query || request.message_type() != MessageType::Query {
+                let response = builder.error_msg(request.header(), ResponseCode::NotImp);
+                response_handle.send_response(response).await
+            } else {
+                let query = request.query();
+                debug!("dns query: {} {}", query.name(), query.query_type());
+                let mut header = Header::response_from_request(request.header());
+                header.set_authoritative(true);
+                let name = Name::from(query.name());
+                let (answers, additionals) =
+                    self.lookup(&name, query.query_type())
+                        .unwrap_or_else(|code| {
+                            // synapse has no authority outside of its zone
+                            header.set_authoritative(code != ResponseCode::Refused);
+                            header.set_response_code(code);
+                            Default::default()
+                        });
+                let response = builder.build(header, answers.iter(), [], [], additionals.iter());
+                response_handle.send_response(response).await
+            };
+
+        result.unwrap_or_else(|e| {
+            error!("dns response failed: {}", e);
+            let mut header = Header::new();
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        })
+    }
+}
+
+/// answer dns queries over udp and tcp
+pub async fn serve(address: SocketAddr, hub: Hub, ttl: u32) -> std::io::Result<()> {
+    let mut server = ServerFuture::new(DnsHandler::new(hub, ttl));
+    server.register_socket(UdpSocket::bind(address).await?);
+    server.register_listener(TcpListener::bind(address).await?, TCP_TIMEOUT);
+    debug!("dns server listening on {}", address);
+    server
+        .block_until_done()
+        .await
+        .map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+    use hickory_resolver::TokioAsyncResolver;
+    use tonic::Request as GrpcRequest;
+
+    use crate::service::ServiceRegistry;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_lookup() {
+        assert_eq!(
+            Lookup::parse("API.service.synapse."),
+            Some(Lookup::Service {
+                key: ServiceKey::new("", "api"),
+                tag: None
+            })
+        );
+        assert_eq!(
+            Lookup::parse("v1.api.service.team-a.synapse"),
+            Some(Lookup::Service {
+                key: ServiceKey::new("team-a", "api"),
+                tag: Some("v1".to_string())
+            })
+        );
+        assert_eq!(
+            Lookup::parse("1.api.instance.default.synapse."),
+            Some(Lookup::Instance {
+                key: ServiceKey::new("", "api"),
+                id: "1".to_string()
+            })
+        );
+        // the instance ids and the tags are case sensitive
+        assert_eq!(
+            Lookup::parse("V1.API.Service.Team-A.SYNAPSE."),
+            Some(Lookup::Service {
+                key: ServiceKey::new("team-a", "api"),
+                tag: Some("V1".to_string())
+            })
+        );
+        assert_eq!(
+            Lookup::parse("Node-A.api.INSTANCE.synapse"),
+            Some(Lookup::Instance {
+                key: ServiceKey::new("", "api"),
+                id: "Node-A".to_string()
+            })
+        );
+        assert_eq!(Lookup::parse("api.service.consul."), None);
+        assert!(!Lookup::in_zone("api.service.consul."));
+        assert!(Lookup::in_zone("web.service.SYNAPSE."));
+        assert_eq!(Lookup::parse("service.synapse."), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let hub = Hub::new();
+        for (id, address, tag, status) in [
+            ("1", "127.0.0.1", "v1", ServiceStatus::Up),
+            ("2", "127.0.0.2", "v2", ServiceStatus::Up),
+            ("3", "127.0.0.3", "v1", ServiceStatus::Down),
+        ] {
+            let instance = ServiceInstance {
+                id: id.to_string(),
+                name: "api".to_string(),
+                address: address.to_string(),
+                port: 8080,
+                tags: vec![tag.to_string()],
+                status: status as i32,
+                ..Default::default()
+            };
+            hub.register_service(GrpcRequest::new(instance))
+                .await
+                .unwrap();
+        }
+
+        // a free port for both udp and tcp
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        drop(socket);
+        tokio::spawn(serve(address, hub.clone(), 5));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let servers = NameServerConfigGroup::from_ips_clear(&[address.ip()], address.port(), true);
+        let config = ResolverConfig::from_parts(None, vec![], servers);
+        let mut opts = ResolverOpts::default();
+        opts.cache_size = 0;
+        let resolver = TokioAsyncResolver::tokio(config, opts);
+
+        let ips: Vec<_> = resolver
+            .ipv4_lookup("api.service.synapse.")
+            .await
+            .unwrap()
+            .iter()
+            .map(|a| a.0)
+            .collect();
+        assert_eq!(
+            ips,
+            vec![Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)]
+        );
+
+        let ips: Vec<_> = resolver
+            .ipv4_lookup("v1.api.service.synapse.")
+            .await
+            .unwrap()
+            .iter()
+            .map(|a| a.0)
+            .collect();
+        assert_eq!(ips, vec![Ipv4Addr::new(127, 0, 0, 1)]);
+
+        let srv = resolver
+            .srv_lookup("v2.api.service.synapse.")
+            .await
+            .unwrap();
+        let record = srv.iter().next().unwrap();
+        assert_eq!(record.port(), 8080);
+        assert_eq!(
+            record.target().to_ascii(),
+            "2.api.instance.default.synapse."
+        );
+        assert_eq!(
+            srv.ip_iter().collect::<Vec<_>>(),
+            vec![IpAddr::from([127, 0, 0, 2])]
+        );
+
+        assert!(resolver.ipv4_lookup("web.service.synapse.").await.is_err());
+
+        let handler = DnsHandler::new(hub, 5);
+        let name = Name::from_ascii("api.service.consul.").unwrap();
+        assert_eq!(
+            handler.lookup(&name, RecordType::A).unwrap_err(),
+            ResponseCode::Refused
+        );
+        let name = Name::from_ascii("web.service.synapse.").unwrap();
+        assert_eq!(
+            handler.lookup(&name, RecordType::A).unwrap_err(),
+            ResponseCode::NXDomain
+        );
+    }
+}
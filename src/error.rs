@@ -0,0 +1,102 @@
+use tonic::{Code, Status};
+
+/// the failures of the registry, returned by the client and mapped to the
+/// grpc status codes by the hub
+#[derive(Debug, thiserror::Error)]
+pub enum SynapseError {
+    #[error("connection error: {0}")]
+    Connection(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("already exists: {0}")]
+    AlreadyExists(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+impl SynapseError {
+    pub fn code(&self) -> Code {
+        match self {
+            Self::Connection(_) => Code::Unavailable,
+            Self::NotFound(_) => Code::NotFound,
+            Self::AlreadyExists(_) => Code::AlreadyExists,
+            Self::InvalidArgument(_) => Code::InvalidArgument,
+            Self::PermissionDenied(_) => Code::PermissionDenied,
+            Self::Conflict(_) => Code::Aborted,
+            Self::Internal(_) => Code::Internal,
+        }
+    }
+
+    fn message(self) -> String {
+        match self {
+            Self::Connection(message)
+            | Self::NotFound(message)
+            | Self::AlreadyExists(message)
+            | Self::InvalidArgument(message)
+            | Self::PermissionDenied(message)
+            | Self::Conflict(message)
+            | Self::Internal(message) => message,
+        }
+    }
+}
+
+impl From<Status> for SynapseError {
+    fn from(status: Status) -> Self {
+        let message = status.message().to_string();
+        match status.code() {
+            Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled => {
+                Self::Connection(message)
+            }
+            Code::NotFound => Self::NotFound(message),
+            Code::AlreadyExists => Self::AlreadyExists(message),
+            Code::InvalidArgument | Code::OutOfRange => Self::InvalidArgument(message),
+            Code::PermissionDenied | Code::Unauthenticated => Self::PermissionDenied(message),
+            Code::Aborted | Code::FailedPrecondition => Self::Conflict(message),
+            _ => Self::Internal(message),
+        }
+    }
+}
+
+impl From<SynapseError> for Status {
+    fn from(error: SynapseError) -> Self {
+        let code = error.code();
+        Status::new(code, error.message())
+    }
+}
+
+impl From<tonic::transport::Error> for SynapseError {
+    fn from(error: tonic::transport::Error) -> Self {
+        Self::Connection(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_mapping() {
+        let errors = [
+            SynapseError::Connection("down".to_string()),
+            SynapseError::NotFound("api".to_string()),
+            SynapseError::AlreadyExists("api".to_string()),
+            SynapseError::InvalidArgument("name".to_string()),
+            SynapseError::PermissionDenied("api".to_string()),
+            SynapseError::Conflict("api".to_string()),
+            SynapseError::Internal("oops".to_string()),
+        ];
+        for error in errors {
+            let expected = error.to_string();
+            let status = Status::from(error);
+            assert_eq!(SynapseError::from(status).to_string(), expected);
+        }
+        let error = SynapseError::from(Status::unauthenticated("token"));
+        assert!(matches!(error, SynapseError::PermissionDenied(_)));
+    }
+}
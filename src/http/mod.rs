@@ -0,0 +1,534 @@
+mod watch;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tonic::{Code, Request, Status};
+use tower::ServiceExt;
+use tracing::{debug, error};
+
+use crate::metrics::METRICS;
+use crate::sd::{target_groups, TargetFilter, TargetGroup};
+use crate::service::hub::{Hub, ServiceKey};
+use crate::service::{
+    OperationStatus, ServiceInstance, ServiceInstanceIdentifier, ServiceRegistry,
+    SubscriptionFilter,
+};
+use crate::tls::PeerIdentity;
+
+/// a grpc status returned as a json error with the matching http status code
+#[derive(Debug)]
+pub struct HttpError(Status);
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: i32,
+    message: String,
+}
+
+impl From<Status> for HttpError {
+    fn from(status: Status) -> Self {
+        Self(status)
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Response {
+        let status = match self.0.code() {
+            Code::InvalidArgument | Code::OutOfRange => StatusCode::BAD_REQUEST,
+            Code::NotFound => StatusCode::NOT_FOUND,
+            Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+            Code::PermissionDenied => StatusCode::FORBIDDEN,
+            Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+            Code::FailedPrecondition => StatusCode::PRECONDITION_FAILED,
+            Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = ErrorBody {
+            code: self.0.code() as i32,
+            message: self.0.message().to_string(),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+impl From<JsonRejection> for HttpError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self(Status::invalid_argument(rejection.body_text()))
+    }
+}
+
+impl From<QueryRejection> for HttpError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self(Status::invalid_argument(rejection.body_text()))
+    }
+}
+
+/// a json request body, a malformed one is rejected with an [`HttpError`]
+/// like every other error of the api
+#[derive(Debug)]
+pub(crate) struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = HttpError;
+
+    async fn from_request(req: axum::extract::Request, state: &S) -> Result<Self, HttpError> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// the query parameters of a request, invalid ones are rejected with an
+/// [`HttpError`] like every other error of the api
+#[derive(Debug)]
+pub(crate) struct QueryParams<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for QueryParams<T>
+where
+    Query<T>: FromRequestParts<S, Rejection = QueryRejection>,
+    S: Send + Sync,
+{
+    type Rejection = HttpError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, HttpError> {
+        let Query(value) = Query::<T>::from_request_parts(parts, state).await?;
+        Ok(Self(value))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct NamespaceQuery {
+    namespace: String,
+}
+
+/// the filters of an instance query, `tags` is comma separated
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct InstanceQuery {
+    namespace: String,
+    tags: String,
+    version: String,
+    healthy: bool,
+}
+
+/// the filters of the prometheus targets, `services` and `tags` are comma
+/// separated
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct TargetQuery {
+    namespace: String,
+    services: String,
+    tags: String,
+    healthy: bool,
+    port_metadata: String,
+}
+
+impl Default for TargetQuery {
+    fn default() -> Self {
+        Self {
+            namespace: String::new(),
+            services: String::new(),
+            tags: String::new(),
+            healthy: true,
+            port_metadata: String::new(),
+        }
+    }
+}
+
+fn split(list: &str) -> Vec<String> {
+    list.split(',')
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+struct CatalogEntry {
+    namespace: String,
+    name: String,
+    instances: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+/// the registry over http/json, sharing the hub of the grpc server
+pub fn router(hub: Hub) -> Router {
+    Router::new()
+        .route("/v1/health", get(health))
+        .route("/metrics", get(metrics))
+        .route("/v1/catalog", get(catalog))
+        .route("/v1/services", post(register))
+        .route("/v1/services/:name", get(instances))
+        .route("/v1/services/:name/:id", delete(deregister))
+        .route("/v1/sd/prometheus", get(prometheus_targets))
+        .route("/v1/watch/sse", get(watch::sse))
+        .route("/v1/watch/ws", get(watch::ws))
+        .with_state(hub)
+}
+
+/// serve the api, over tls when a configuration is given; clients must then
+/// present a certificate if it has a client ca, as over grpc
+pub async fn serve(
+    address: SocketAddr,
+    hub: Hub,
+    tls: Option<Arc<ServerConfig>>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    debug!("http api listening on {}", address);
+    match tls {
+        Some(tls) => serve_tls(listener, router(hub), tls).await,
+        None => axum::serve(listener, router(hub)).await,
+    }
+}
+
+/// the identity of a client presenting a certificate is set as a
+/// [`PeerIdentity`] extension of its requests
+async fn serve_tls(
+    listener: TcpListener,
+    router: Router,
+    config: Arc<ServerConfig>,
+) -> std::io::Result<()> {
+    let acceptor = TlsAcceptor::from(config);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("accept http connection failed: {}", e);
+                continue;
+            }
+        };
+        let (acceptor, router) = (acceptor.clone(), router.clone());
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("tls handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| PeerIdentity::from_der(certs.first()?));
+            let service = service_fn(move |mut req: axum::http::Request<Incoming>| {
+                if let Some(ref identity) = identity {
+                    req.extensions_mut().insert(identity.clone());
+                }
+                router.clone().oneshot(req)
+            });
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("http connection with {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// a request to the hub carrying the identity of the client
+fn request<T>(message: T, identity: Option<Extension<PeerIdentity>>) -> Request<T> {
+    let mut req = Request::new(message);
+    if let Some(Extension(identity)) = identity {
+        req.extensions_mut().insert(identity);
+    }
+    req
+}
+
+async fn health() -> Json<Health> {
+    Json(Health { status: "SERVING" })
+}
+
+/// the metrics in the prometheus text format
+async fn metrics(State(hub): State<Hub>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(&hub),
+    )
+}
+
+/// every namespace when none is given
+async fn catalog(
+    State(hub): State<Hub>,
+    QueryParams(query): QueryParams<NamespaceQuery>,
+) -> Json<Vec<CatalogEntry>> {
+    let namespace = (!query.namespace.is_empty()).then_some(query.namespace.as_str());
+    let catalog = hub
+        .catalog(namespace)
+        .into_iter()
+        .map(|(key, instances)| CatalogEntry {
+            namespace: key.namespace,
+            name: key.name,
+            instances,
+        })
+        .collect();
+    Json(catalog)
+}
+
+async fn register(
+    State(hub): State<Hub>,
+    identity: Option<Extension<PeerIdentity>>,
+    JsonBody(instance): JsonBody<ServiceInstance>,
+) -> Result<Json<OperationStatus>, HttpError> {
+    let res = hub.register_service(request(instance, identity)).await?;
+    Ok(Json(res.into_inner()))
+}
+
+async fn deregister(
+    State(hub): State<Hub>,
+    identity: Option<Extension<PeerIdentity>>,
+    Path((name, id)): Path<(String, String)>,
+    QueryParams(query): QueryParams<NamespaceQuery>,
+) -> Result<Json<OperationStatus>, HttpError> {
+    let identifier = ServiceInstanceIdentifier::new(name, id).with_namespace(query.namespace);
+    let res = hub
+        .unregister_service(request(identifier, identity))
+        .await?;
+    Ok(Json(res.into_inner()))
+}
+
+async fn instances(
+    State(hub): State<Hub>,
+    Path(name): Path<String>,
+    QueryParams(query): QueryParams<InstanceQuery>,
+) -> Json<Vec<ServiceInstance>> {
+    let filter = SubscriptionFilter {
+        tags: split(&query.tags),
+        version: query.version,
+        healthy_only: query.healthy,
+        ..Default::default()
+    };
+    let mut instances: Vec<_> = hub
+        .instances(&ServiceKey::new(query.namespace, name))
+        .into_iter()
+        .filter(|instance| filter.matches(instance))
+        .collect();
+    instances.sort_by(|a, b| a.id.cmp(&b.id));
+    Json(instances)
+}
+
+/// the targets of the prometheus http service discovery
+async fn prometheus_targets(
+    State(hub): State<Hub>,
+    QueryParams(query): QueryParams<TargetQuery>,
+) -> Json<Vec<TargetGroup>> {
+    let filter = TargetFilter {
+        namespace: (!query.namespace.is_empty()).then_some(query.namespace),
+        services: split(&query.services),
+        tags: split(&query.tags),
+        healthy_only: query.healthy,
+        port_metadata: (!query.port_metadata.is_empty()).then_some(query.port_metadata),
+    };
+    Json(target_groups(&hub, &filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path as FilePath;
+
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Method, Request as HttpRequest};
+    use serde_json::{json, Value};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use crate::error::SynapseError;
+    use crate::service::hub::HubConfig;
+    use crate::tls::{http_tls_config, Authorize, Operation};
+
+    use super::*;
+
+    async fn call(
+        hub: &Hub,
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = HttpRequest::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        let body = body.map_or(Body::empty(), |body| Body::from(body.to_string()));
+        let response = router(hub.clone())
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_http_api() {
+        let hub = Hub::new();
+        let instance = json!({
+            "id": "1",
+            "name": "api",
+            "address": "127.0.0.1",
+            "port": 8080,
+            "tags": ["v1"],
+            "scheme": "https",
+        });
+        let (status, body) = call(&hub, Method::POST, "/v1/services", Some(instance)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["success"], true);
+
+        let (status, body) = call(&hub, Method::GET, "/v1/services/api?tags=v1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["port"], 8080);
+        assert_eq!(body[0]["scheme"], "HTTPS");
+        assert_eq!(body[0]["status"], "UP");
+        let (_, body) = call(&hub, Method::GET, "/v1/services/api?tags=v2", None).await;
+        assert_eq!(body, json!([]));
+
+        let (_, body) = call(&hub, Method::GET, "/v1/catalog", None).await;
+        assert_eq!(
+            body,
+            json!([{"namespace": "default", "name": "api", "instances": 1}])
+        );
+
+        let (_, body) = call(&hub, Method::GET, "/v1/sd/prometheus?services=api", None).await;
+        assert_eq!(body[0]["targets"], json!(["127.0.0.1:8080"]));
+        assert_eq!(body[0]["labels"]["__meta_synapse_tags"], ",v1,");
+
+        let invalid = json!({"id": "2", "name": "", "address": "127.0.0.1", "port": 8080});
+        let (status, _) = call(&hub, Method::POST, "/v1/services", Some(invalid)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let invalid = json!({"id": "2", "name": "api", "address": "127.0.0.1", "status": "LOST"});
+        let (status, body) = call(&hub, Method::POST, "/v1/services", Some(invalid)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], Code::InvalidArgument as i32);
+        assert!(body["message"].as_str().unwrap().contains("LOST"));
+        let (status, body) = call(&hub, Method::GET, "/v1/services/api?healthy=maybe", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], Code::InvalidArgument as i32);
+
+        let (status, _) = call(&hub, Method::DELETE, "/v1/services/api/1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = call(&hub, Method::DELETE, "/v1/services/api/1", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], Code::NotFound as i32);
+    }
+
+    /// only the clients presenting the certificate of the test data
+    #[derive(Debug)]
+    struct TestClient;
+
+    impl Authorize for TestClient {
+        fn authorize(
+            &self,
+            identity: Option<&PeerIdentity>,
+            _operation: Operation<'_>,
+        ) -> Result<(), SynapseError> {
+            match identity.and_then(|identity| identity.common_name.as_deref()) {
+                Some("client") => Ok(()),
+                _ => Err(SynapseError::PermissionDenied("unknown client".to_string())),
+            }
+        }
+    }
+
+    fn testdata(name: &str) -> std::path::PathBuf {
+        FilePath::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/tls/testdata")
+            .join(name)
+    }
+
+    /// register an instance over https, with the client certificate when asked
+    async fn register_over_tls(port: u16, with_cert: bool) -> Result<StatusCode, String> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut include_bytes!("../tls/testdata/ca.pem").as_slice())
+        {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = if with_cert {
+            let certs =
+                rustls_pemfile::certs(&mut include_bytes!("../tls/testdata/client.pem").as_slice())
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+            let key = rustls_pemfile::private_key(
+                &mut include_bytes!("../tls/testdata/client.key").as_slice(),
+            )
+            .unwrap()
+            .unwrap();
+            builder.with_client_auth_cert(certs, key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .map_err(|e| e.to_string())?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::spawn(connection);
+        let instance = json!({"id": "1", "name": "api", "address": "127.0.0.1", "port": 8080});
+        let request = HttpRequest::post("/v1/services")
+            .header("host", "localhost")
+            .header("content-type", "application/json")
+            .body(Body::from(instance.to_string()))
+            .unwrap();
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.status())
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let hub = Hub::with_config(HubConfig {
+            authorizer: Some(Arc::new(TestClient)),
+            ..Default::default()
+        });
+        let config = http_tls_config(
+            &testdata("server.pem"),
+            &testdata("server.key"),
+            Some(&testdata("ca.pem")),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_tls(listener, router(hub.clone()), config));
+
+        // a client without a certificate is rejected
+        assert!(register_over_tls(port, false).await.is_err());
+        assert!(hub.catalog(None).is_empty());
+
+        // the identity of the client reaches the authorization hook
+        assert_eq!(register_over_tls(port, true).await, Ok(StatusCode::OK));
+        assert_eq!(hub.catalog(None).len(), 1);
+    }
+}
@@ -0,0 +1,308 @@
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::time::Duration;
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use tokio::time;
+use tonic::{Request, Status};
+use tracing::{debug, error};
+
+use crate::http::{HttpError, QueryParams};
+use crate::service::hub::Hub;
+use crate::service::{EventKind, ServiceEvent, ServiceRegistry, SubscribeRequest};
+
+/// keeps proxies from closing idle watches
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// the header of the id of the last event an event source received, sent
+/// back when it reconnects
+const LAST_EVENT_ID: &str = "last-event-id";
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<ServiceEvent, Status>> + Send>>;
+
+/// the services to watch, `services` is comma separated. the current
+/// instances are sent first unless `snapshot` is false
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct WatchQuery {
+    namespace: String,
+    service: String,
+    services: String,
+    prefix: String,
+    all: bool,
+    resume_from: u64,
+    epoch: u64,
+    snapshot: bool,
+}
+
+impl Default for WatchQuery {
+    fn default() -> Self {
+        Self {
+            namespace: String::new(),
+            service: String::new(),
+            services: String::new(),
+            prefix: String::new(),
+            all: false,
+            resume_from: 0,
+            epoch: 0,
+            snapshot: true,
+        }
+    }
+}
+
+/// the same events as the grpc subscriptions
+async fn subscribe(hub: &Hub, query: WatchQuery) -> Result<EventStream, HttpError> {
+    let req = SubscribeRequest {
+        service: query.service,
+        namespace: query.namespace,
+        services: query
+            .services
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect(),
+        prefix: query.prefix,
+        all: query.all,
+        resume_from: query.resume_from,
+        epoch: query.epoch,
+        ..Default::default()
+    };
+    debug!("watch: {:?}", req);
+    let events = if query.snapshot {
+        hub.subscribe_to_service(Request::new(req)).await?
+    } else {
+        hub.subscribe(Request::new(req)).await?
+    };
+    Ok(events.into_inner())
+}
+
+/// the id of an event, `<epoch>-<revision>`
+fn event_id(event: &ServiceEvent) -> String {
+    format!("{}-{}", event.epoch, event.revision)
+}
+
+/// the epoch and the revision of an event id
+fn parse_event_id(id: &str) -> Option<(u64, u64)> {
+    let (epoch, revision) = id.split_once('-')?;
+    Some((epoch.parse().ok()?, revision.parse().ok()?))
+}
+
+/// an event per change, named after its kind; a failed subscription ends with
+/// an `error` event. the live changes and the SNAPSHOT_END of a snapshot are
+/// identified by their epoch and revision, the events of a snapshot are not,
+/// so a reconnecting event source resumes after the `Last-Event-ID` it sends
+/// only once it received a complete snapshot, unless `resume_from` is given
+pub(crate) async fn sse(
+    State(hub): State<Hub>,
+    QueryParams(mut query): QueryParams<WatchQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, HttpError> {
+    let last_event_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|id| id.to_str().ok())
+        .and_then(parse_event_id);
+    if let (0, Some((epoch, revision))) = (query.resume_from, last_event_id) {
+        query.epoch = epoch;
+        query.resume_from = revision;
+    }
+    // a resumed or live only subscription does not start with a snapshot
+    let mut synced = query.resume_from != 0 || !query.snapshot;
+    let events = subscribe(&hub, query).await?.map(move |event| {
+        let event = match event {
+            Ok(event) => {
+                match event.kind() {
+                    // a snapshot follows
+                    EventKind::Resync => synced = false,
+                    EventKind::SnapshotEnd => synced = true,
+                    _ => {}
+                }
+                let sse = Event::default().event(event.kind().as_str_name());
+                let sse = if synced {
+                    sse.id(event_id(&event))
+                } else {
+                    sse
+                };
+                sse.json_data(&event).unwrap_or_else(|e| {
+                    error!("serialize event failed: {}", e);
+                    Event::default().event("error").data(e.to_string())
+                })
+            }
+            Err(status) => Event::default().event("error").data(status.message()),
+        };
+        Ok(event)
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::new().interval(HEARTBEAT_INTERVAL)))
+}
+
+/// a json text message per change, pinged every heartbeat; a failed
+/// subscription closes the socket with its reason
+pub(crate) async fn ws(
+    ws: WebSocketUpgrade,
+    State(hub): State<Hub>,
+    QueryParams(query): QueryParams<WatchQuery>,
+) -> Result<Response, HttpError> {
+    let events = subscribe(&hub, query).await?;
+    Ok(ws.on_upgrade(move |socket| forward(socket, events)))
+}
+
+async fn forward(mut socket: WebSocket, mut events: EventStream) {
+    let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+    // the first tick completes immediately
+    heartbeat.tick().await;
+    loop {
+        let message = tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(event)) => match serde_json::to_string(&event) {
+                    Ok(json) => Message::Text(json),
+                    Err(e) => {
+                        error!("serialize event failed: {}", e);
+                        continue;
+                    }
+                },
+                Some(Err(status)) => {
+                    let _ = socket.send(close(status)).await;
+                    return;
+                }
+                None => return,
+            },
+            _ = heartbeat.tick() => Message::Ping(Vec::new()),
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        if socket.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn close(status: Status) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: close_code::AGAIN,
+        reason: status.message().to_string().into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request as HttpRequest, StatusCode};
+    use tower::ServiceExt;
+
+    use crate::http::router;
+    use crate::service::ServiceInstance;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sse() {
+        let hub = Hub::new();
+        let mut instance = ServiceInstance {
+            id: "1".to_string(),
+            name: "api".to_string(),
+            address: "127.0.0.1".to_string(),
+            port: 8080,
+            ..Default::default()
+        };
+        hub.register_service(Request::new(instance.clone()))
+            .await
+            .unwrap();
+
+        let request = HttpRequest::get("/v1/watch/sse?services=api,web")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(hub.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while !received.contains("event: SNAPSHOT_END") {
+            let chunk = body.next().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let id = |revision: u64| format!("id: {}-{}\n", hub.epoch(), revision);
+        // only the end of the snapshot is identified
+        assert!(!received.contains("event: ADDED\nid:"));
+        assert!(received.contains(&format!("event: SNAPSHOT_END\n{}", id(1))));
+        assert!(received.contains(r#""port":8080"#));
+        assert!(received.contains(r#""kind":"ADDED""#));
+
+        // a reconnecting event source resumes after its last event
+        instance.id = "2".to_string();
+        hub.register_service(Request::new(instance)).await.unwrap();
+        let request = HttpRequest::get("/v1/watch/sse?services=api,web")
+            .header(LAST_EVENT_ID, format!("{}-1", hub.epoch()))
+            .body(Body::empty())
+            .unwrap();
+        let response = router(hub.clone()).oneshot(request).await.unwrap();
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while !received.contains("event: SNAPSHOT_END") {
+            let chunk = body.next().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        assert!(received.contains(&format!("event: ADDED\n{}", id(2))));
+        assert!(!received.contains("\"id\":\"1\""));
+
+        let request = HttpRequest::get("/v1/watch/sse")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(hub).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sse_disconnect_mid_snapshot() {
+        let hub = Hub::new();
+        for id in ["1", "2"] {
+            let instance = ServiceInstance {
+                id: id.to_string(),
+                name: "api".to_string(),
+                address: "127.0.0.1".to_string(),
+                port: 8080,
+                ..Default::default()
+            };
+            hub.register_service(Request::new(instance)).await.unwrap();
+        }
+        let watch = |last_event_id: Option<String>| {
+            let mut request = HttpRequest::get("/v1/watch/sse?service=api");
+            if let Some(id) = last_event_id {
+                request = request.header(LAST_EVENT_ID, id);
+            }
+            router(hub.clone()).oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // the event source of another server run gets a fresh snapshot, and
+        // the connection drops after its first instance
+        let stale = Some(format!("{}-1", hub.epoch().wrapping_add(1)));
+        let response = watch(stale.clone()).await.unwrap();
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while !received.contains("event: ADDED") {
+            let chunk = body.next().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        drop(body);
+        assert!(received.contains("event: RESYNC"));
+        assert!(!received.contains("id:"));
+
+        // with no new id, it reconnects with the same one and gets the whole
+        // snapshot again
+        let response = watch(stale).await.unwrap();
+        let mut body = response.into_body().into_data_stream();
+        let mut received = String::new();
+        while !received.contains("event: SNAPSHOT_END") {
+            let chunk = body.next().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        assert_eq!(received.matches("event: ADDED").count(), 2);
+        assert!(received.contains(&format!("id: {}-2\n", hub.epoch())));
+    }
+}
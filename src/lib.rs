@@ -1,4 +1,12 @@
+pub mod dns;
+pub mod error;
 pub mod health;
+pub mod http;
+pub mod metrics;
 pub(crate) mod pb;
+pub mod render;
+pub mod sd;
 pub mod service;
+pub mod tls;
 mod typos;
+pub mod xds;
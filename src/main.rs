@@ -1,19 +1,154 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use dotenv::dotenv;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use tonic::transport::Server;
-use tracing::Level;
+use tracing::{error, warn, Level};
 
 use synapse::health::HealthServer;
 use synapse::health::HealthService;
+use synapse::metrics::RpcMetricsLayer;
+use synapse::render::{RenderConfig, Renderer, TemplateSpec};
+use synapse::sd::{self, TargetFilter};
+use synapse::service::client::ServiceClient;
 use synapse::service::hub;
+use synapse::service::subscription::SlowConsumerPolicy;
 use synapse::service::ServiceRegistryServer;
+use synapse::tls::{self, IdentityInterceptor};
+use synapse::xds::{AggregatedDiscoveryServiceServer, XdsServer};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// address of the grpc server, the synapse to render from in render mode
     #[clap(long, env = "SERVICE_ADDRESS", default_value = "127.0.0.1:8500")]
     address: SocketAddr,
+    /// address of the http/json api
+    #[clap(long, env = "HTTP_ADDRESS", default_value = "127.0.0.1:8501")]
+    http_address: SocketAddr,
+    /// address of the dns interface, over udp and tcp
+    #[clap(long, env = "DNS_ADDRESS", default_value = "127.0.0.1:8600")]
+    dns_address: SocketAddr,
+    /// time to live of the dns records, in seconds
+    #[clap(long, env = "DNS_TTL", default_value_t = 5)]
+    dns_ttl: u32,
+    /// file of the prometheus file service discovery, not written when not given
+    #[clap(long, env = "FILE_SD_PATH")]
+    file_sd_path: Option<PathBuf>,
+    /// namespace of the exported targets, every namespace when not given
+    #[clap(long, env = "FILE_SD_NAMESPACE")]
+    file_sd_namespace: Option<String>,
+    /// services of the exported targets, comma separated, every service when empty
+    #[clap(long, env = "FILE_SD_SERVICES", value_delimiter = ',')]
+    file_sd_services: Vec<String>,
+    /// tags the exported instances must all have, comma separated
+    #[clap(long, env = "FILE_SD_TAGS", value_delimiter = ',')]
+    file_sd_tags: Vec<String>,
+    /// metadata holding the port to scrape, the instance port when not given
+    #[clap(long, env = "FILE_SD_PORT_METADATA")]
+    file_sd_port_metadata: Option<String>,
+    /// seconds between two checks of the registry for changes to write
+    #[clap(long, env = "FILE_SD_INTERVAL", default_value_t = 5)]
+    file_sd_interval: u64,
+    /// certificate of the grpc server and the http api, which are served over
+    /// tls when given
+    #[clap(long, env = "TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// private key of the certificate of the grpc server and the http api
+    #[clap(long, env = "TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// ca of the client certificates, clients must present one when given
+    #[clap(long, env = "TLS_CLIENT_CA", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// capacity of the event queue of each subscriber
+    #[clap(long, env = "CHANNEL_CAPACITY", default_value_t = 256)]
+    channel_capacity: usize,
+    /// number of recent events kept to replay them to resuming subscribers
+    #[clap(long, env = "JOURNAL_CAPACITY", default_value_t = 1024)]
+    journal_capacity: usize,
+    /// what to do with a subscriber whose queue is full
+    #[clap(long, env = "SLOW_CONSUMER_POLICY", value_enum, default_value_t)]
+    slow_consumer_policy: SlowConsumerPolicy,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// render templates with the instances of services, every time they change
+    Render(RenderArgs),
+}
+
+#[derive(Args)]
+struct RenderArgs {
+    /// namespace of the services
+    #[clap(long, env = "RENDER_NAMESPACE", default_value = "default")]
+    namespace: String,
+    /// template to render, as <source>:<destination>, repeatable
+    #[clap(long = "template", required = true)]
+    templates: Vec<TemplateSpec>,
+    /// services given to the templates, comma separated
+    #[clap(long = "service", value_delimiter = ',', required = true)]
+    services: Vec<String>,
+    /// command run after a template rendered a different file
+    #[clap(long, env = "RENDER_COMMAND")]
+    command: Option<String>,
+    /// milliseconds without changes before rendering
+    #[clap(long, env = "RENDER_WAIT", default_value_t = 500)]
+    wait: u64,
+    /// most milliseconds a change waits to be rendered while changes keep coming
+    #[clap(long, env = "RENDER_MAX_WAIT", default_value_t = 5000)]
+    max_wait: u64,
+    /// ca of the certificate of synapse, which is connected to over tls when
+    /// given
+    #[clap(long, env = "RENDER_TLS_CA")]
+    tls_ca: Option<PathBuf>,
+    /// certificate presented to a synapse enforcing mutual tls
+    #[clap(long, env = "RENDER_TLS_CERT", requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// private key of the client certificate
+    #[clap(long, env = "RENDER_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// name the certificate of synapse is checked against, the address by
+    /// default
+    #[clap(long, env = "RENDER_TLS_DOMAIN", requires = "tls_ca")]
+    tls_domain: Option<String>,
+}
+
+/// render the templates from the synapse at the address
+async fn render(address: SocketAddr, args: RenderArgs) {
+    let mut builder = ServiceClient::builder()
+        .server(address.ip().to_string(), address.port())
+        .namespace(args.namespace);
+    if let Some(ca) = &args.tls_ca {
+        let identity = args.tls_cert.as_deref().zip(args.tls_key.as_deref());
+        match tls::client_tls_config(ca, identity, args.tls_domain.as_deref()) {
+            Ok(config) => builder = builder.tls_config(config),
+            Err(e) => {
+                error!("load tls configuration failed: {}", e);
+                return;
+            }
+        }
+    }
+    let client = builder.build().await;
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            error!("connect to synapse failed: {}", e);
+            return;
+        }
+    };
+    let config = RenderConfig {
+        templates: args.templates,
+        services: args.services,
+        command: args.command,
+        wait: Duration::from_millis(args.wait),
+        max_wait: Duration::from_millis(args.max_wait),
+    };
+    if let Err(e) = Renderer::new(client, config).run().await {
+        error!("render failed: {}", e);
+    }
 }
 
 #[tokio::main]
@@ -26,13 +161,80 @@ async fn main() {
     dotenv().ok();
 
     let cli = Cli::parse();
+    if let Some(Command::Render(args)) = cli.command {
+        render(cli.address, args).await;
+        return;
+    }
 
-    let h = hub::Hub::new();
+    let mut builder = Server::builder();
+    let mut http_tls = None;
+    if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+        let client_ca = cli.tls_client_ca.as_deref();
+        let configs = tls::server_tls_config(cert, key, client_ca)
+            .and_then(|config| Ok((config, tls::http_tls_config(cert, key, client_ca)?)));
+        let (config, http_config) = match configs {
+            Ok(configs) => configs,
+            Err(e) => {
+                error!("load tls configuration failed: {}", e);
+                return;
+            }
+        };
+        builder = match builder.tls_config(config) {
+            Ok(builder) => builder,
+            Err(e) => {
+                error!("configure tls failed: {}", e);
+                return;
+            }
+        };
+        http_tls = Some(http_config);
+    }
+
+    let h = hub::Hub::with_config(hub::HubConfig {
+        channel_capacity: cli.channel_capacity,
+        journal_capacity: cli.journal_capacity,
+        slow_consumer_policy: cli.slow_consumer_policy,
+        authorizer: None,
+    });
     let server = HealthServer::new(HealthService {});
-    let registry_server = ServiceRegistryServer::new(h);
-    Server::builder()
+    let registry_server = ServiceRegistryServer::with_interceptor(h.clone(), IdentityInterceptor);
+    let xds_server = AggregatedDiscoveryServiceServer::new(XdsServer::new(h.clone()));
+
+    if cli.tls_client_ca.is_some() {
+        warn!(
+            "the dns interface on {} answers every client, mutual tls only protects the grpc server and the http api",
+            cli.dns_address
+        );
+    }
+    let dns_hub = h.clone();
+    tokio::spawn(async move {
+        if let Err(e) = synapse::dns::serve(cli.dns_address, dns_hub, cli.dns_ttl).await {
+            error!("dns interface failed: {}", e);
+        }
+    });
+
+    if let Some(path) = cli.file_sd_path {
+        let filter = TargetFilter {
+            namespace: cli.file_sd_namespace,
+            services: cli.file_sd_services,
+            tags: cli.file_sd_tags,
+            healthy_only: true,
+            port_metadata: cli.file_sd_port_metadata,
+        };
+        let interval = Duration::from_secs(cli.file_sd_interval);
+        tokio::spawn(sd::file_sd(h.clone(), path, filter, interval));
+    }
+
+    let http_address = cli.http_address;
+    tokio::spawn(async move {
+        if let Err(e) = synapse::http::serve(http_address, h, http_tls).await {
+            error!("http api failed: {}", e);
+        }
+    });
+    builder
+        .layer(RpcMetricsLayer)
         .add_service(server)
         .add_service(registry_server)
+        .add_service(xds_server)
         .serve(cli.address)
         .await
         .unwrap();
@@ -83,11 +285,13 @@ mod tests {
             health_check: None,
             status: 0,
             scheme: Scheme::Http as i32,
+            namespace: "".to_string(),
         };
         client.register_service(req).await.unwrap();
         let response = client
             .query_services(QueryRequest {
                 name: "test".to_string(),
+                ..Default::default()
             })
             .await
             .unwrap();
@@ -105,6 +309,7 @@ mod tests {
         let response = client
             .query_services(QueryRequest {
                 name: "ws".to_string(),
+                ..Default::default()
             })
             .await
             .unwrap();
@@ -130,6 +335,7 @@ mod tests {
             health_check: None,
             status: 0,
             scheme: Scheme::Http as i32,
+            namespace: "".to_string(),
         };
         client.register_service(req).await.unwrap();
     }
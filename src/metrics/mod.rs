@@ -0,0 +1,323 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use tonic::codegen::http;
+use tower::{Layer, Service};
+
+use crate::service::hub::{Hub, ServiceKey};
+use crate::service::subscription::SlowConsumerPolicy;
+use crate::service::ServiceStatus;
+
+/// the metrics of the process, rendered by the `/metrics` endpoint of the
+/// http api
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// the grpc methods served by synapse, the label of the other paths is
+/// `unknown` so clients cannot grow the series without bound
+const RPC_METHODS: &[&str] = &[
+    "/service_registry.ServiceRegistry/RegisterService",
+    "/service_registry.ServiceRegistry/UnregisterService",
+    "/service_registry.ServiceRegistry/QueryServices",
+    "/service_registry.ServiceRegistry/Subscribe",
+    "/service_registry.ServiceRegistry/SubscribeToService",
+    "/service_registry.ServiceRegistry/SubscribeBatched",
+    "/health_check.Health/Check",
+    "/health_check.Health/Watch",
+    "/envoy.service.discovery.v3.AggregatedDiscoveryService/StreamAggregatedResources",
+    "/envoy.service.discovery.v3.AggregatedDiscoveryService/DeltaAggregatedResources",
+];
+
+/// the method label of a grpc path
+fn method_label(path: &str) -> &'static str {
+    RPC_METHODS
+        .iter()
+        .find(|method| **method == path)
+        .copied()
+        .unwrap_or("unknown")
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    /// registrations which added or changed an instance, per service
+    pub registrations: IntCounterVec,
+    /// registered instances, per service and status
+    instances: IntGaugeVec,
+    /// health probes, per service and result
+    pub health_probes: IntCounterVec,
+    /// the duration of the health probes, per service
+    pub health_probe_duration: HistogramVec,
+    /// open subscriptions, per service they select
+    subscribers: IntGaugeVec,
+    /// events waiting in the queues of the subscribers
+    queued_events: IntGauge,
+    /// subscribers whose queue overflowed, per slow consumer policy
+    pub queue_overflows: IntCounterVec,
+    /// events dropped from the queue of a slow subscriber
+    pub dropped_events: IntCounter,
+    /// the duration of the grpc calls until their response, per method
+    pub rpc_duration: HistogramVec,
+    /// the services which had instances, their gauges drop to 0 rather than
+    /// disappear once the last instance is gone
+    services: Mutex<HashSet<ServiceKey>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("synapse".to_string()), None).unwrap();
+        let registrations = IntCounterVec::new(
+            Opts::new(
+                "registrations_total",
+                "registrations which added or changed an instance",
+            ),
+            &["namespace", "service"],
+        )
+        .unwrap();
+        let instances = IntGaugeVec::new(
+            Opts::new("instances", "registered instances by status"),
+            &["namespace", "service", "status"],
+        )
+        .unwrap();
+        let health_probes = IntCounterVec::new(
+            Opts::new("health_probes_total", "health probes by result"),
+            &["namespace", "service", "result"],
+        )
+        .unwrap();
+        let health_probe_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "health_probe_duration_seconds",
+                "duration of the health probes",
+            ),
+            &["namespace", "service"],
+        )
+        .unwrap();
+        let subscribers = IntGaugeVec::new(
+            Opts::new("subscribers", "open subscriptions selecting the service"),
+            &["namespace", "service"],
+        )
+        .unwrap();
+        let queued_events = IntGauge::new(
+            "subscriber_queued_events",
+            "events waiting in the queues of the subscribers",
+        )
+        .unwrap();
+        let queue_overflows = IntCounterVec::new(
+            Opts::new(
+                "subscriber_queue_overflows_total",
+                "subscribers whose queue overflowed, by slow consumer policy",
+            ),
+            &["policy"],
+        )
+        .unwrap();
+        let dropped_events = IntCounter::new(
+            "subscriber_dropped_events_total",
+            "events dropped from the queue of a slow subscriber",
+        )
+        .unwrap();
+        let rpc_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "rpc_duration_seconds",
+                "duration of the grpc calls until their response",
+            ),
+            &["method"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(registrations.clone())).unwrap();
+        registry.register(Box::new(instances.clone())).unwrap();
+        registry.register(Box::new(health_probes.clone())).unwrap();
+        registry
+            .register(Box::new(health_probe_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(subscribers.clone())).unwrap();
+        registry.register(Box::new(queued_events.clone())).unwrap();
+        registry
+            .register(Box::new(queue_overflows.clone()))
+            .unwrap();
+        registry.register(Box::new(dropped_events.clone())).unwrap();
+        registry.register(Box::new(rpc_duration.clone())).unwrap();
+
+        Self {
+            registry,
+            registrations,
+            instances,
+            health_probes,
+            health_probe_duration,
+            subscribers,
+            queued_events,
+            queue_overflows,
+            dropped_events,
+            rpc_duration,
+            services: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// count the overflow of the queue of a subscriber, dropping its events
+    pub fn queue_overflow(&self, policy: SlowConsumerPolicy, dropped: usize) {
+        let policy = match policy {
+            SlowConsumerPolicy::Drop => "drop",
+            SlowConsumerPolicy::Resync => "resync",
+            SlowConsumerPolicy::Disconnect => "disconnect",
+        };
+        self.queue_overflows.with_label_values(&[policy]).inc();
+        self.dropped_events.inc_by(dropped as u64);
+    }
+
+    /// the metrics in the prometheus text format, the gauges of the registry
+    /// are refreshed from the hub first
+    pub fn render(&self, hub: &Hub) -> String {
+        let mut services = self.services.lock().unwrap_or_else(PoisonError::into_inner);
+        services.extend(hub.catalog(None).into_iter().map(|(key, _)| key));
+        for key in services.iter() {
+            let ServiceKey { namespace, name } = key;
+            let (up, down): (Vec<_>, Vec<_>) = hub
+                .instances(key)
+                .into_iter()
+                .partition(|instance| instance.status() == ServiceStatus::Up);
+            self.instances
+                .with_label_values(&[namespace, name, "up"])
+                .set(up.len() as i64);
+            self.instances
+                .with_label_values(&[namespace, name, "down"])
+                .set(down.len() as i64);
+            self.subscribers
+                .with_label_values(&[namespace, name])
+                .set(hub.subscribers(key) as i64);
+        }
+        drop(services);
+        self.queued_events.set(hub.queued_events() as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// records the duration of the grpc calls of a server
+#[derive(Debug, Clone, Default)]
+pub struct RpcMetricsLayer;
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetrics { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RpcMetrics<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for RpcMetrics<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        // the path of a grpc call is /<package>.<service>/<method>
+        let method = method_label(req.uri().path());
+        let start = Instant::now();
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let res = future.await;
+            // streams are measured until their response headers
+            METRICS
+                .rpc_duration
+                .with_label_values(&[method])
+                .observe(start.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Request;
+
+    use crate::service::{
+        ServiceInstance, ServiceInstanceIdentifier, ServiceRegistry, SubscribeRequest,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render() {
+        let hub = Hub::new();
+        for (id, status) in [("1", ServiceStatus::Up), ("2", ServiceStatus::Down)] {
+            let instance = ServiceInstance {
+                id: id.to_string(),
+                name: "metrics-api".to_string(),
+                address: "127.0.0.1".to_string(),
+                port: 8080,
+                status: status as i32,
+                ..Default::default()
+            };
+            hub.register_service(Request::new(instance)).await.unwrap();
+        }
+        let _subscription = hub
+            .subscribe(Request::new(SubscribeRequest::for_services([
+                "metrics-api",
+            ])))
+            .await
+            .unwrap();
+
+        let metrics = METRICS.render(&hub);
+        for line in [
+            r#"synapse_instances{namespace="default",service="metrics-api",status="up"} 1"#,
+            r#"synapse_instances{namespace="default",service="metrics-api",status="down"} 1"#,
+            r#"synapse_registrations_total{namespace="default",service="metrics-api"} 2"#,
+            r#"synapse_subscribers{namespace="default",service="metrics-api"} 1"#,
+        ] {
+            assert!(metrics.contains(line), "{} not in {}", line, metrics);
+        }
+
+        // the series of a service without instances stay at 0
+        for id in ["1", "2"] {
+            let identifier =
+                ServiceInstanceIdentifier::new("metrics-api".to_string(), id.to_string());
+            hub.unregister_service(Request::new(identifier))
+                .await
+                .unwrap();
+        }
+        let metrics = METRICS.render(&hub);
+        for line in [
+            r#"synapse_instances{namespace="default",service="metrics-api",status="up"} 0"#,
+            r#"synapse_instances{namespace="default",service="metrics-api",status="down"} 0"#,
+        ] {
+            assert!(metrics.contains(line), "{} not in {}", line, metrics);
+        }
+    }
+
+    #[test]
+    fn test_method_label() {
+        assert_eq!(
+            method_label("/service_registry.ServiceRegistry/Subscribe"),
+            "/service_registry.ServiceRegistry/Subscribe"
+        );
+        assert_eq!(
+            method_label("/service_registry.ServiceRegistry/Nope"),
+            "unknown"
+        );
+        assert_eq!(method_label("/random/path"), "unknown");
+    }
+}
@@ -1,4 +1,5 @@
 mod health_check;
+mod serde_enum;
 mod service_registry;
 
 pub use health_check::*;
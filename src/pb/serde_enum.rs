@@ -0,0 +1,56 @@
+//! the enumerations of the json messages, serialized by the names of their
+//! values as in the protobuf definition; the names are read case insensitively
+//! and the numbers are still accepted
+
+macro_rules! enum_names {
+    ($($module:ident => $enum:ident),* $(,)?) => {
+        $(
+            pub(crate) mod $module {
+                use serde::de::Error;
+                use serde::{Deserialize, Deserializer, Serializer};
+
+                use crate::pb::$enum;
+
+                #[derive(Deserialize)]
+                #[serde(untagged)]
+                enum Value {
+                    Name(String),
+                    Number(i32),
+                }
+
+                pub(crate) fn serialize<S: Serializer>(
+                    value: &i32,
+                    serializer: S,
+                ) -> Result<S::Ok, S::Error> {
+                    match $enum::try_from(*value) {
+                        Ok(value) => serializer.serialize_str(value.as_str_name()),
+                        Err(_) => serializer.serialize_i32(*value),
+                    }
+                }
+
+                pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+                    deserializer: D,
+                ) -> Result<i32, D::Error> {
+                    match Value::deserialize(deserializer)? {
+                        Value::Name(name) => $enum::from_str_name(&name.to_ascii_uppercase())
+                            .map(|value| value as i32)
+                            .ok_or_else(|| {
+                                D::Error::custom(format!(
+                                    "unknown {} {}",
+                                    stringify!($enum),
+                                    name
+                                ))
+                            }),
+                        Value::Number(value) => Ok(value),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+enum_names! {
+    scheme => Scheme,
+    service_status => ServiceStatus,
+    event_kind => EventKind,
+}
@@ -1,4 +1,6 @@
 // This file is @generated by prost-build.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ServiceInstance {
@@ -13,6 +15,7 @@ pub struct ServiceInstance {
     #[prost(string, tag = "5")]
     pub version: ::prost::alloc::string::String,
     #[prost(enumeration = "Scheme", tag = "6")]
+    #[serde(with = "crate::pb::serde_enum::scheme")]
     pub scheme: i32,
     #[prost(map = "string, string", tag = "7")]
     pub metadata:
@@ -22,8 +25,14 @@ pub struct ServiceInstance {
     #[prost(message, optional, tag = "9")]
     pub health_check: ::core::option::Option<HealthCheck>,
     #[prost(enumeration = "ServiceStatus", tag = "10")]
+    #[serde(with = "crate::pb::serde_enum::service_status")]
     pub status: i32,
+    /// 服务所属的命名空间，为空时使用默认命名空间
+    #[prost(string, tag = "11")]
+    pub namespace: ::prost::alloc::string::String,
 }
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct HealthCheck {
@@ -36,17 +45,89 @@ pub struct HealthCheck {
     #[prost(int32, tag = "4")]
     pub retries: i32,
     #[prost(enumeration = "Scheme", tag = "6")]
+    #[serde(with = "crate::pb::serde_enum::scheme")]
     pub scheme: i32,
     #[prost(string, optional, tag = "7")]
     pub tls_domain: ::core::option::Option<::prost::alloc::string::String>,
 }
+/// 订阅流中的变更事件
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ServiceEvent {
+    #[prost(enumeration = "EventKind", tag = "1")]
+    #[serde(with = "crate::pb::serde_enum::event_kind")]
+    pub kind: i32,
+    /// SNAPSHOT_END和RESYNC事件中为空
+    #[prost(message, optional, tag = "2")]
+    pub instance: ::core::option::Option<ServiceInstance>,
+    /// 单调递增的全局版本号
+    #[prost(uint64, tag = "3")]
+    pub revision: u64,
+    /// 服务端本次运行的随机标识，重启后版本号从头开始，恢复订阅时需一并携带
+    #[prost(uint64, tag = "4")]
+    pub epoch: u64,
+}
+/// 订阅请求，service、services、prefix和all可以组合使用，订阅的是它们的并集
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct SubscribeRequest {
     #[prost(string, tag = "1")]
     pub service: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub namespace: ::prost::alloc::string::String,
+    /// 同时订阅多个服务
+    #[prost(string, repeated, tag = "3")]
+    pub services: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 订阅名称以该前缀开头的所有服务，包括之后注册的服务
+    #[prost(string, tag = "4")]
+    pub prefix: ::prost::alloc::string::String,
+    /// 订阅命名空间内的所有服务
+    #[prost(bool, tag = "5")]
+    pub all: bool,
+    /// 断线重连时携带最后收到的事件版本号，只重放之后的事件并以SNAPSHOT_END结束；
+    /// 版本过旧时发送RESYNC和完整快照。为0时表示不恢复
+    #[prost(uint64, tag = "6")]
+    pub resume_from: u64,
+    /// 只订阅满足过滤条件的实例，实例不再满足条件时会收到REMOVED事件
+    #[prost(message, optional, tag = "7")]
+    pub filter: ::core::option::Option<SubscriptionFilter>,
+    /// SubscribeBatched的防抖窗口，单位毫秒，收到第一个变更后等待该时长再发送批量事件
+    #[prost(uint32, tag = "8")]
+    pub debounce_ms: u32,
+    /// resume_from所属的epoch，与服务端当前epoch不同时发送RESYNC和完整快照
+    #[prost(uint64, tag = "9")]
+    pub epoch: u64,
+}
+/// 防抖窗口内的变更，每个实例只保留最终状态
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ServiceEventBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub events: ::prost::alloc::vec::Vec<ServiceEvent>,
+}
+/// 订阅过滤条件，所有条件同时满足时实例才会被发送
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SubscriptionFilter {
+    /// 实例需包含所有这些标签
+    #[prost(string, repeated, tag = "1")]
+    pub tags: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 实例元数据需包含所有这些键值对
+    #[prost(map = "string, string", tag = "2")]
+    pub metadata:
+        ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
+    /// 为空时不过滤版本
+    #[prost(string, tag = "3")]
+    pub version: ::prost::alloc::string::String,
+    /// 只包含UP状态的实例
+    #[prost(bool, tag = "4")]
+    pub healthy_only: bool,
 }
 /// 操作状态定义
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct OperationStatus {
@@ -67,6 +148,9 @@ pub struct ServiceInstanceIdentifier {
     /// 服务名称
     #[prost(string, tag = "2")]
     pub name: ::prost::alloc::string::String,
+    /// 命名空间，为空时使用默认命名空间
+    #[prost(string, tag = "3")]
+    pub namespace: ::prost::alloc::string::String,
 }
 /// 查询请求定义
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -75,6 +159,12 @@ pub struct QueryRequest {
     /// 可以按服务名称查询
     #[prost(string, tag = "1")]
     pub name: ::prost::alloc::string::String,
+    /// 命名空间，为空时使用默认命名空间
+    #[prost(string, tag = "2")]
+    pub namespace: ::prost::alloc::string::String,
+    /// 显式跨命名空间查询，此时忽略namespace
+    #[prost(bool, tag = "3")]
+    pub all_namespaces: bool,
 }
 /// 查询响应定义
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -99,6 +189,8 @@ pub struct Service {
     pub active: i32,
     #[prost(enumeration = "Scheme", tag = "6")]
     pub scheme: i32,
+    #[prost(string, tag = "7")]
+    pub namespace: ::prost::alloc::string::String,
 }
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
@@ -152,6 +244,51 @@ impl ServiceStatus {
         }
     }
 }
+/// 变更事件类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum EventKind {
+    /// 新注册的实例
+    Added = 0,
+    /// 已存在的实例重新注册并发生了变化
+    Updated = 1,
+    /// 实例被注销
+    Removed = 2,
+    /// 健康检查导致实例状态变化
+    StatusChanged = 3,
+    /// 初始快照发送完毕，之后均为增量事件
+    SnapshotEnd = 4,
+    /// 订阅者落后太多，需丢弃本地视图，随后会发送完整快照并以SNAPSHOT_END结束
+    Resync = 5,
+}
+impl EventKind {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            EventKind::Added => "ADDED",
+            EventKind::Updated => "UPDATED",
+            EventKind::Removed => "REMOVED",
+            EventKind::StatusChanged => "STATUS_CHANGED",
+            EventKind::SnapshotEnd => "SNAPSHOT_END",
+            EventKind::Resync => "RESYNC",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ADDED" => Some(Self::Added),
+            "UPDATED" => Some(Self::Updated),
+            "REMOVED" => Some(Self::Removed),
+            "STATUS_CHANGED" => Some(Self::StatusChanged),
+            "SNAPSHOT_END" => Some(Self::SnapshotEnd),
+            "RESYNC" => Some(Self::Resync),
+            _ => None,
+        }
+    }
+}
 /// Generated client implementations.
 pub mod service_registry_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -307,7 +444,7 @@ pub mod service_registry_client {
             &mut self,
             request: impl tonic::IntoRequest<super::SubscribeRequest>,
         ) -> std::result::Result<
-            tonic::Response<tonic::codec::Streaming<super::Service>>,
+            tonic::Response<tonic::codec::Streaming<super::ServiceEvent>>,
             tonic::Status,
         > {
             self.inner.ready().await.map_err(|e| {
@@ -331,7 +468,7 @@ pub mod service_registry_client {
             &mut self,
             request: impl tonic::IntoRequest<super::SubscribeRequest>,
         ) -> std::result::Result<
-            tonic::Response<tonic::codec::Streaming<super::Service>>,
+            tonic::Response<tonic::codec::Streaming<super::ServiceEvent>>,
             tonic::Status,
         > {
             self.inner.ready().await.map_err(|e| {
@@ -351,6 +488,31 @@ pub mod service_registry_client {
             ));
             self.inner.server_streaming(req, path, codec).await
         }
+        /// 与SubscribeToService相同，但在debounce_ms窗口内合并变更，每个实例只发送最终状态
+        pub async fn subscribe_batched(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SubscribeRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::ServiceEventBatch>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/service_registry.ServiceRegistry/SubscribeBatched",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "service_registry.ServiceRegistry",
+                "SubscribeBatched",
+            ));
+            self.inner.server_streaming(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -377,7 +539,7 @@ pub mod service_registry_server {
         ) -> std::result::Result<tonic::Response<super::QueryResponse>, tonic::Status>;
         /// Server streaming response type for the Subscribe method.
         type SubscribeStream: tonic::codegen::tokio_stream::Stream<
-                Item = std::result::Result<super::Service, tonic::Status>,
+                Item = std::result::Result<super::ServiceEvent, tonic::Status>,
             > + Send
             + 'static;
         async fn subscribe(
@@ -386,7 +548,7 @@ pub mod service_registry_server {
         ) -> std::result::Result<tonic::Response<Self::SubscribeStream>, tonic::Status>;
         /// Server streaming response type for the SubscribeToService method.
         type SubscribeToServiceStream: tonic::codegen::tokio_stream::Stream<
-                Item = std::result::Result<super::Service, tonic::Status>,
+                Item = std::result::Result<super::ServiceEvent, tonic::Status>,
             > + Send
             + 'static;
         /// 订阅服务实例，与Subscribe不同的是，在订阅的同时，会返回订阅的所有服务实例
@@ -394,6 +556,16 @@ pub mod service_registry_server {
             &self,
             request: tonic::Request<super::SubscribeRequest>,
         ) -> std::result::Result<tonic::Response<Self::SubscribeToServiceStream>, tonic::Status>;
+        /// Server streaming response type for the SubscribeBatched method.
+        type SubscribeBatchedStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::ServiceEventBatch, tonic::Status>,
+            > + Send
+            + 'static;
+        /// 与SubscribeToService相同，但在debounce_ms窗口内合并变更，每个实例只发送最终状态
+        async fn subscribe_batched(
+            &self,
+            request: tonic::Request<super::SubscribeRequest>,
+        ) -> std::result::Result<tonic::Response<Self::SubscribeBatchedStream>, tonic::Status>;
     }
     /// gRPC服务接口定义，用于服务注册、注销和查询
     #[derive(Debug)]
@@ -604,7 +776,7 @@ pub mod service_registry_server {
                         tonic::server::ServerStreamingService<super::SubscribeRequest>
                         for SubscribeSvc<T>
                     {
-                        type Response = super::Service;
+                        type Response = super::ServiceEvent;
                         type ResponseStream = T::SubscribeStream;
                         type Future =
                             BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
@@ -649,7 +821,7 @@ pub mod service_registry_server {
                         tonic::server::ServerStreamingService<super::SubscribeRequest>
                         for SubscribeToServiceSvc<T>
                     {
-                        type Response = super::Service;
+                        type Response = super::ServiceEvent;
                         type ResponseStream = T::SubscribeToServiceStream;
                         type Future =
                             BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
@@ -687,6 +859,51 @@ pub mod service_registry_server {
                     };
                     Box::pin(fut)
                 }
+                "/service_registry.ServiceRegistry/SubscribeBatched" => {
+                    #[allow(non_camel_case_types)]
+                    struct SubscribeBatchedSvc<T: ServiceRegistry>(pub Arc<T>);
+                    impl<T: ServiceRegistry>
+                        tonic::server::ServerStreamingService<super::SubscribeRequest>
+                        for SubscribeBatchedSvc<T>
+                    {
+                        type Response = super::ServiceEventBatch;
+                        type ResponseStream = T::SubscribeBatchedStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SubscribeRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ServiceRegistry>::subscribe_batched(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SubscribeBatchedSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => Box::pin(async move {
                     Ok(http::Response::builder()
                         .status(200)
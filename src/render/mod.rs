@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use minijinja::{context, Environment};
+use thiserror::Error;
+use tokio::fs;
+use tokio::process::Command;
+use tokio::time::{self, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::error::SynapseError;
+use crate::service::client::{CacheUpdater, InstanceCache, ServiceClient};
+use crate::service::hub::ServiceName;
+use crate::service::validation::normalize_name;
+use crate::service::{EventKind, ServiceInstance, ServiceStatus, SubscribeRequest};
+
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("template error: {0}")]
+    Template(#[from] minijinja::Error),
+}
+
+/// a template and the file it is rendered to, parsed from
+/// `<source>:<destination>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateSpec {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+}
+
+impl FromStr for TemplateSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((source, destination)) if !source.is_empty() && !destination.is_empty() => {
+                Ok(Self {
+                    source: source.into(),
+                    destination: destination.into(),
+                })
+            }
+            _ => Err(format!("expected <source>:<destination>, got {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderConfig {
+    pub templates: Vec<TemplateSpec>,
+    /// the services whose instances are given to the templates
+    pub services: Vec<ServiceName>,
+    /// the command run after a template rendered a different file
+    pub command: Option<String>,
+    /// the quiet period after a change before rendering
+    pub wait: Duration,
+    /// the longest a change waits to be rendered while changes keep coming
+    pub max_wait: Duration,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            templates: Vec::new(),
+            services: Vec::new(),
+            command: None,
+            wait: Duration::from_millis(500),
+            max_wait: Duration::from_secs(5),
+        }
+    }
+}
+
+/// renders templates with the instances of services, every time they change.
+///
+/// the templates are rendered with `services`, the instances which are up of
+/// each service by name:
+///
+/// ```jinja
+/// upstream api {
+/// {% for instance in services.api %}
+///     server {{ instance.address }}:{{ instance.port }};
+/// {% endfor %}
+/// }
+/// ```
+pub struct Renderer {
+    client: ServiceClient,
+    config: RenderConfig,
+}
+
+impl Renderer {
+    pub fn new(client: ServiceClient, config: RenderConfig) -> Self {
+        Self { client, config }
+    }
+
+    /// render the templates until the subscription to synapse gives up; the
+    /// templates are first rendered once every service was received
+    pub async fn run(mut self) -> Result<(), SynapseError> {
+        let names: Vec<ServiceName> = self
+            .config
+            .services
+            .iter()
+            .map(|name| normalize_name(name))
+            .collect();
+        let cache = InstanceCache::default();
+        let mut updater = CacheUpdater::new(cache.clone(), names.clone());
+        let mut rx = self
+            .client
+            .subscribe(SubscribeRequest::for_services(names.clone()))
+            .await?;
+
+        let mut synced = false;
+        // the first and the last change waiting to be rendered
+        let mut pending: Option<(Instant, Instant)> = None;
+        loop {
+            let deadline = pending
+                .map(|(first, last)| (last + self.config.wait).min(first + self.config.max_wait));
+            tokio::select! {
+                event = rx.recv() => {
+                    let Some(event) = event else {
+                        return Err(SynapseError::Connection(
+                            "subscription to synapse closed".to_string(),
+                        ));
+                    };
+                    synced |= event.kind() == EventKind::SnapshotEnd;
+                    updater.apply(event);
+                    if synced {
+                        let now = Instant::now();
+                        pending = Some((pending.map_or(now, |(first, _)| first), now));
+                    }
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    pending = None;
+                    self.render(&cache, &names).await;
+                }
+            }
+        }
+    }
+
+    async fn render(&self, cache: &InstanceCache, names: &[ServiceName]) {
+        let services: BTreeMap<_, _> = names
+            .iter()
+            .map(|name| {
+                let mut instances: Vec<_> = cache
+                    .instances(name)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|instance| instance.status() == ServiceStatus::Up)
+                    .collect();
+                instances.sort_by(|a, b| a.id.cmp(&b.id));
+                (name.clone(), instances)
+            })
+            .collect();
+
+        let mut changed = false;
+        for template in &self.config.templates {
+            match render_template(template, &services).await {
+                Ok(true) => {
+                    info!("rendered {:?}", template.destination);
+                    changed = true;
+                }
+                Ok(false) => debug!("{:?} is unchanged", template.destination),
+                Err(e) => error!("render {:?} failed: {}", template.source, e),
+            }
+        }
+        if let (true, Some(command)) = (changed, &self.config.command) {
+            run_command(command).await;
+        }
+    }
+}
+
+/// render a template, returns whether the destination changed
+pub async fn render_template(
+    template: &TemplateSpec,
+    services: &BTreeMap<ServiceName, Vec<ServiceInstance>>,
+) -> Result<bool, RenderError> {
+    let source = fs::read_to_string(&template.source).await?;
+    let rendered = Environment::new().render_str(&source, context! { services => services })?;
+    let current = fs::read_to_string(&template.destination).await.ok();
+    if current.as_deref() == Some(rendered.as_str()) {
+        return Ok(false);
+    }
+    write_atomic(&template.destination, &rendered).await?;
+    Ok(true)
+}
+
+/// write the file through a temporary file renamed over it, so readers never
+/// see a partially written file
+async fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await
+}
+
+async fn run_command(command: &str) {
+    debug!("run {}", command);
+    match Command::new("sh").arg("-c").arg(command).status().await {
+        Ok(status) if status.success() => info!("{} succeeded", command),
+        Ok(status) => warn!("{} failed: {}", command, status),
+        Err(e) => error!("run {} failed: {}", command, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_spec() {
+        assert_eq!(
+            "upstream.tmpl:/etc/nginx/upstream.conf".parse(),
+            Ok(TemplateSpec {
+                source: "upstream.tmpl".into(),
+                destination: "/etc/nginx/upstream.conf".into(),
+            })
+        );
+        assert!("upstream.tmpl".parse::<TemplateSpec>().is_err());
+        assert!(":upstream.conf".parse::<TemplateSpec>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_template() {
+        let dir = std::env::temp_dir().join(format!("synapse-render-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template = TemplateSpec {
+            source: dir.join("upstream.tmpl"),
+            destination: dir.join("upstream.conf"),
+        };
+        std::fs::write(
+            &template.source,
+            "{% for i in services.api %}server {{ i.address }}:{{ i.port }};\n{% endfor %}",
+        )
+        .unwrap();
+        let instance = ServiceInstance {
+            id: "1".to_string(),
+            name: "api".to_string(),
+            address: "10.0.0.1".to_string(),
+            port: 8080,
+            ..Default::default()
+        };
+        let mut services = BTreeMap::from([("api".to_string(), vec![instance])]);
+
+        assert!(render_template(&template, &services).await.unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&template.destination).unwrap(),
+            "server 10.0.0.1:8080;\n"
+        );
+        // the file is not rewritten when nothing changed
+        assert!(!render_template(&template, &services).await.unwrap());
+
+        services.get_mut("api").unwrap().clear();
+        assert!(render_template(&template, &services).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&template.destination).unwrap(), "");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::{fs, time};
+use tracing::{debug, error};
+
+use crate::service::hub::Hub;
+use crate::service::validation::normalize_name;
+use crate::service::{ServiceInstance, ServiceStatus};
+
+/// the prefix of the labels of the targets, dropped by prometheus after
+/// relabeling
+pub const LABEL_PREFIX: &str = "__meta_synapse_";
+
+/// a target group of the prometheus http and file service discovery
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetGroup {
+    pub targets: Vec<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// the instances exported as targets
+#[derive(Debug, Clone)]
+pub struct TargetFilter {
+    /// every namespace when none is given
+    pub namespace: Option<String>,
+    /// every service when empty
+    pub services: Vec<String>,
+    /// the instances having all the tags
+    pub tags: Vec<String>,
+    /// only the instances which are up
+    pub healthy_only: bool,
+    /// the metadata holding the port to scrape, the instances without it are
+    /// not exported; the port of the instance when none is given
+    pub port_metadata: Option<String>,
+}
+
+impl Default for TargetFilter {
+    fn default() -> Self {
+        Self {
+            namespace: None,
+            services: Vec::new(),
+            tags: Vec::new(),
+            healthy_only: true,
+            port_metadata: None,
+        }
+    }
+}
+
+impl TargetFilter {
+    /// the target of the instance, None if it is not exported
+    fn target(&self, instance: &ServiceInstance) -> Option<String> {
+        if self.healthy_only && instance.status() != ServiceStatus::Up {
+            return None;
+        }
+        if !self.tags.iter().all(|tag| instance.tags.contains(tag)) {
+            return None;
+        }
+        let port = match &self.port_metadata {
+            Some(key) => instance.metadata.get(key)?.parse::<u16>().ok()?,
+            None => instance.port as u16,
+        };
+        if instance.address.contains(':') {
+            Some(format!("[{}]:{}", instance.address, port))
+        } else {
+            Some(format!("{}:{}", instance.address, port))
+        }
+    }
+}
+
+/// the target groups of the exported instances, one per instance so its tags
+/// and metadata are kept as labels
+pub fn target_groups(hub: &Hub, filter: &TargetFilter) -> Vec<TargetGroup> {
+    let services: Vec<_> = filter
+        .services
+        .iter()
+        .map(|name| normalize_name(name))
+        .collect();
+    hub.catalog(filter.namespace.as_deref())
+        .into_iter()
+        .filter(|(key, _)| services.is_empty() || services.contains(&key.name))
+        .flat_map(|(key, _)| {
+            let mut instances = hub.instances(&key);
+            instances.sort_by(|a, b| a.id.cmp(&b.id));
+            instances
+        })
+        .filter_map(|instance| {
+            let target = filter.target(&instance)?;
+            Some(TargetGroup {
+                targets: vec![target],
+                labels: labels(&instance),
+            })
+        })
+        .collect()
+}
+
+/// the labels of an instance, the tags are joined between commas so a single
+/// tag is matched by `.*,<tag>,.*`
+fn labels(instance: &ServiceInstance) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    let mut label = |name: &str, value: &str| {
+        labels.insert(format!("{}{}", LABEL_PREFIX, name), value.to_string());
+    };
+    label("namespace", &instance.namespace);
+    label("service", &instance.name);
+    label("instance_id", &instance.id);
+    label("address", &instance.address);
+    label("port", &instance.port.to_string());
+    label("version", &instance.version);
+    label("tags", &format!(",{},", instance.tags.join(",")));
+    for (key, value) in &instance.metadata {
+        label(&format!("metadata_{}", label_name(key)), value);
+    }
+    labels
+}
+
+/// the metadata key with the characters not allowed in a label name replaced
+fn label_name(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// write the target groups to a file of the prometheus file service
+/// discovery
+pub async fn write_file(path: &Path, groups: &[TargetGroup]) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(groups)?;
+    // prometheus must never read a partially written file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, json).await?;
+    fs::rename(&tmp, path).await
+}
+
+/// keep the file of the prometheus file service discovery up to date, it is
+/// rewritten when the registry changed since the last interval
+pub async fn file_sd(hub: Hub, path: PathBuf, filter: TargetFilter, interval: Duration) {
+    let mut written = None;
+    let mut interval = time::interval(interval);
+    loop {
+        interval.tick().await;
+        let revision = hub.revision();
+        if written == Some(revision) {
+            continue;
+        }
+        let groups = target_groups(&hub, &filter);
+        match write_file(&path, &groups).await {
+            Ok(()) => {
+                debug!("{} targets written to {:?}", groups.len(), path);
+                written = Some(revision);
+            }
+            Err(e) => error!("write {:?} failed: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Request;
+
+    use crate::service::ServiceRegistry;
+
+    use super::*;
+
+    async fn register(hub: &Hub, id: &str, name: &str, tags: &[&str], status: ServiceStatus) {
+        let mut instance = ServiceInstance {
+            id: id.to_string(),
+            name: name.to_string(),
+            address: "127.0.0.1".to_string(),
+            port: 8080,
+            version: "1.0".to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            status: status as i32,
+            ..Default::default()
+        };
+        instance
+            .metadata
+            .insert("metrics-port".to_string(), "9090".to_string());
+        hub.register_service(Request::new(instance)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_target_groups() {
+        let hub = Hub::new();
+        register(&hub, "1", "api", &["v1", "prod"], ServiceStatus::Up).await;
+        register(&hub, "2", "api", &["v1"], ServiceStatus::Up).await;
+        register(&hub, "3", "api", &["v1", "prod"], ServiceStatus::Down).await;
+        register(&hub, "4", "web", &["prod"], ServiceStatus::Up).await;
+
+        let groups = target_groups(&hub, &TargetFilter::default());
+        let ids: Vec<_> = groups
+            .iter()
+            .map(|group| group.labels["__meta_synapse_instance_id"].as_str())
+            .collect();
+        assert_eq!(ids, vec!["1", "2", "4"]);
+        let labels = &groups[0].labels;
+        assert_eq!(groups[0].targets, vec!["127.0.0.1:8080"]);
+        assert_eq!(labels["__meta_synapse_service"], "api");
+        assert_eq!(labels["__meta_synapse_namespace"], "default");
+        assert_eq!(labels["__meta_synapse_tags"], ",v1,prod,");
+        assert_eq!(labels["__meta_synapse_metadata_metrics_port"], "9090");
+
+        let filter = TargetFilter {
+            services: vec!["API".to_string()],
+            tags: vec!["prod".to_string()],
+            port_metadata: Some("metrics-port".to_string()),
+            ..Default::default()
+        };
+        let groups = target_groups(&hub, &filter);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].targets, vec!["127.0.0.1:9090"]);
+    }
+
+    #[tokio::test]
+    async fn test_write_file() {
+        let hub = Hub::new();
+        register(&hub, "1", "api", &[], ServiceStatus::Up).await;
+        let path = std::env::temp_dir().join(format!("synapse-sd-{}.json", std::process::id()));
+
+        let groups = target_groups(&hub, &TargetFilter::default());
+        write_file(&path, &groups).await.unwrap();
+        let written: Vec<TargetGroup> =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, groups);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
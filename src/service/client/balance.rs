@@ -0,0 +1,322 @@
+use std::fmt::Debug;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use rand::Rng;
+
+use crate::service::hub::ServiceId;
+use crate::service::ServiceInstance;
+
+/// the metadata holding the weight of an instance, used by [`Weighted`]
+pub const WEIGHT_METADATA: &str = "weight";
+
+/// the highest weight of an instance, greater weights are capped
+pub const MAX_WEIGHT: u64 = u32::MAX as u64;
+
+/// picks the instance serving a request among the instances of a service
+pub trait LoadBalancer: Debug + Send + Sync {
+    /// the index of the picked instance, `key` is the request key used by
+    /// hashing strategies
+    fn pick(&self, instances: &[ServiceInstance], key: Option<&str>) -> Option<usize>;
+
+    /// a request was sent to the instance
+    fn started(&self, _instance: &ServiceInstance) {}
+
+    /// a request sent to the instance completed
+    fn completed(&self, _instance: &ServiceInstance) {}
+}
+
+/// an instance picked by a [`LoadBalancer`], the request is completed when it
+/// is dropped
+#[derive(Debug)]
+pub struct Picked {
+    instance: ServiceInstance,
+    balancer: Arc<dyn LoadBalancer>,
+}
+
+impl Picked {
+    pub(crate) fn new(instance: ServiceInstance, balancer: Arc<dyn LoadBalancer>) -> Self {
+        balancer.started(&instance);
+        Self { instance, balancer }
+    }
+}
+
+impl Deref for Picked {
+    type Target = ServiceInstance;
+
+    fn deref(&self) -> &Self::Target {
+        &self.instance
+    }
+}
+
+impl Drop for Picked {
+    fn drop(&mut self) {
+        self.balancer.completed(&self.instance);
+    }
+}
+
+/// picks the instances in turn
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl LoadBalancer for RoundRobin {
+    fn pick(&self, instances: &[ServiceInstance], _key: Option<&str>) -> Option<usize> {
+        if instances.is_empty() {
+            return None;
+        }
+        Some(self.next.fetch_add(1, Ordering::Relaxed) % instances.len())
+    }
+}
+
+/// picks an instance at random
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl LoadBalancer for Random {
+    fn pick(&self, instances: &[ServiceInstance], _key: Option<&str>) -> Option<usize> {
+        if instances.is_empty() {
+            return None;
+        }
+        Some(rand::thread_rng().gen_range(0..instances.len()))
+    }
+}
+
+/// picks an instance at random in proportion to its `weight` metadata,
+/// instances without a valid weight count as 1, a weight of 0 is never picked
+/// and weights are capped at [`MAX_WEIGHT`]
+#[derive(Debug, Default)]
+pub struct Weighted;
+
+fn weight(instance: &ServiceInstance) -> u64 {
+    instance
+        .metadata
+        .get(WEIGHT_METADATA)
+        .and_then(|weight| weight.parse::<u64>().ok())
+        .map_or(1, |weight| weight.min(MAX_WEIGHT))
+}
+
+impl LoadBalancer for Weighted {
+    fn pick(&self, instances: &[ServiceInstance], _key: Option<&str>) -> Option<usize> {
+        let total = instances.iter().map(weight).fold(0u64, u64::saturating_add);
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0..total);
+        instances.iter().position(|instance| {
+            let weight = weight(instance);
+            if point < weight {
+                return true;
+            }
+            point -= weight;
+            false
+        })
+    }
+}
+
+/// the number of requests in flight to each instance
+#[derive(Debug, Default)]
+struct Outstanding {
+    requests: DashMap<ServiceId, usize>,
+}
+
+impl Outstanding {
+    fn get(&self, instance: &ServiceInstance) -> usize {
+        self.requests.get(&instance.id).map_or(0, |count| *count)
+    }
+
+    fn started(&self, instance: &ServiceInstance) {
+        *self.requests.entry(instance.id.clone()).or_default() += 1;
+    }
+
+    fn completed(&self, instance: &ServiceInstance) {
+        self.requests.remove_if_mut(&instance.id, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+/// picks the instance with the least requests in flight
+#[derive(Debug, Default)]
+pub struct LeastOutstanding {
+    outstanding: Outstanding,
+}
+
+impl LoadBalancer for LeastOutstanding {
+    fn pick(&self, instances: &[ServiceInstance], _key: Option<&str>) -> Option<usize> {
+        (0..instances.len()).min_by_key(|&i| self.outstanding.get(&instances[i]))
+    }
+
+    fn started(&self, instance: &ServiceInstance) {
+        self.outstanding.started(instance)
+    }
+
+    fn completed(&self, instance: &ServiceInstance) {
+        self.outstanding.completed(instance)
+    }
+}
+
+/// picks two instances at random and keeps the one with the least requests in
+/// flight
+#[derive(Debug, Default)]
+pub struct PowerOfTwoChoices {
+    outstanding: Outstanding,
+}
+
+impl LoadBalancer for PowerOfTwoChoices {
+    fn pick(&self, instances: &[ServiceInstance], _key: Option<&str>) -> Option<usize> {
+        match instances.len() {
+            0 => None,
+            1 => Some(0),
+            len => {
+                let mut rng = rand::thread_rng();
+                let a = rng.gen_range(0..len);
+                let b = (a + rng.gen_range(1..len)) % len;
+                let outstanding = |i: usize| self.outstanding.get(&instances[i]);
+                Some(if outstanding(b) < outstanding(a) {
+                    b
+                } else {
+                    a
+                })
+            }
+        }
+    }
+
+    fn started(&self, instance: &ServiceInstance) {
+        self.outstanding.started(instance)
+    }
+
+    fn completed(&self, instance: &ServiceInstance) {
+        self.outstanding.completed(instance)
+    }
+}
+
+/// picks the same instance for the same request key while it is available,
+/// only the keys of an instance which comes or goes are moved.
+///
+/// uses rendezvous hashing with 64-bit FNV-1a, so every client picks the
+/// same instance whatever its build; requests without a key are picked at
+/// random.
+#[derive(Debug, Default)]
+pub struct ConsistentHash;
+
+/// the 64-bit FNV-1a hash of the parts, each followed by a zero byte so the
+/// parts cannot run into each other
+fn fnv1a(parts: &[&str]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    parts
+        .iter()
+        .flat_map(|part| part.bytes().chain([0]))
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(PRIME)
+        })
+}
+
+impl LoadBalancer for ConsistentHash {
+    fn pick(&self, instances: &[ServiceInstance], key: Option<&str>) -> Option<usize> {
+        let Some(key) = key else {
+            return Random.pick(instances, None);
+        };
+        (0..instances.len()).max_by_key(|&i| fnv1a(&[&instances[i].id, key]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instances(weights: &[&str]) -> Vec<ServiceInstance> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| {
+                let mut instance = ServiceInstance {
+                    id: i.to_string(),
+                    name: "api".to_string(),
+                    ..Default::default()
+                };
+                if !weight.is_empty() {
+                    instance
+                        .metadata
+                        .insert(WEIGHT_METADATA.to_string(), weight.to_string());
+                }
+                instance
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let instances = instances(&["", "", ""]);
+        let balancer = RoundRobin::default();
+        let picks: Vec<_> = (0..4)
+            .map(|_| balancer.pick(&instances, None).unwrap())
+            .collect();
+        assert_eq!(picks, vec![0, 1, 2, 0]);
+        assert_eq!(balancer.pick(&[], None), None);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let weighted = instances(&["0", "3", ""]);
+        let mut picks = [0; 3];
+        for _ in 0..1000 {
+            picks[Weighted.pick(&weighted, None).unwrap()] += 1;
+        }
+        assert_eq!(picks[0], 0);
+        assert!(picks[1] > picks[2]);
+        assert_eq!(Weighted.pick(&weighted[..1], None), None);
+
+        // the weights are capped, their sum never overflows
+        let max = u64::MAX.to_string();
+        let capped = instances(&[&max, &max, "0"]);
+        assert_eq!(weight(&capped[0]), MAX_WEIGHT);
+        assert!(Weighted.pick(&capped, None).unwrap() < 2);
+    }
+
+    #[test]
+    fn test_outstanding_requests() {
+        let instances = instances(&["", ""]);
+        let balancer: Arc<dyn LoadBalancer> = Arc::new(LeastOutstanding::default());
+        let first = Picked::new(instances[0].clone(), balancer.clone());
+        assert_eq!(balancer.pick(&instances, None), Some(1));
+        drop(first);
+        assert_eq!(balancer.pick(&instances, None), Some(0));
+
+        let balancer: Arc<dyn LoadBalancer> = Arc::new(PowerOfTwoChoices::default());
+        let _first = Picked::new(instances[0].clone(), balancer.clone());
+        assert_eq!(balancer.pick(&instances, None), Some(1));
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        // the hash is stable across builds, "a" is hashed as "a\0"
+        assert_eq!(fnv1a(&[]), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(&["a"]), 0x089be207b544f1e4);
+
+        let instances = instances(&["", "", "", ""]);
+        let keys: Vec<_> = (0..100).map(|i| format!("user-{}", i)).collect();
+        let picks: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                instances[ConsistentHash.pick(&instances, Some(key)).unwrap()]
+                    .id
+                    .clone()
+            })
+            .collect();
+
+        // only the keys of the removed instance move
+        let remaining = &instances[1..];
+        for (key, pick) in keys.iter().zip(picks) {
+            let repick = &remaining[ConsistentHash.pick(remaining, Some(key)).unwrap()].id;
+            if pick != instances[0].id {
+                assert_eq!(repick, &pick);
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tokio::sync::watch;
+
+use crate::service::hub::ServiceName;
+use crate::service::validation::normalize_name;
+use crate::service::{EventKind, ServiceEvent, ServiceInstance};
+
+/// a local copy of the instances of the watched services, kept up to date by
+/// the subscription streams of the client.
+///
+/// the last known instances are kept when synapse is unreachable, so reads
+/// never wait on the network. the names of the services are normalized like
+/// synapse does, `API` and `api` are the same service.
+#[derive(Debug, Clone, Default)]
+pub struct InstanceCache {
+    services: Arc<DashMap<ServiceName, watch::Sender<Vec<ServiceInstance>>>>,
+}
+
+impl InstanceCache {
+    /// the cached instances of a service, `None` if it is not watched
+    pub fn instances(&self, name: &str) -> Option<Vec<ServiceInstance>> {
+        self.services
+            .get(&normalize_name(name))
+            .map(|tx| tx.borrow().clone())
+    }
+
+    /// a receiver notified every time the instances of a watched service change
+    pub fn changes(&self, name: &str) -> Option<watch::Receiver<Vec<ServiceInstance>>> {
+        self.services
+            .get(&normalize_name(name))
+            .map(|tx| tx.subscribe())
+    }
+
+    fn track(&self, name: &str) {
+        self.services
+            .entry(normalize_name(name))
+            .or_insert_with(|| watch::channel(Vec::new()).0);
+    }
+
+    fn replace(&self, name: &str, instances: Vec<ServiceInstance>) {
+        if let Some(tx) = self.services.get(&normalize_name(name)) {
+            tx.send_replace(instances);
+        }
+    }
+
+    fn update(&self, kind: EventKind, instance: ServiceInstance) {
+        if let Some(tx) = self.services.get(&normalize_name(&instance.name)) {
+            tx.send_if_modified(|instances| apply(instances, kind, instance));
+        }
+    }
+}
+
+/// applies the events of one subscription to the cache.
+///
+/// a snapshot is collected aside and swapped in once complete, the cache keeps
+/// serving the previous instances while a subscriber is resynced.
+pub(crate) struct CacheUpdater {
+    cache: InstanceCache,
+    names: Vec<ServiceName>,
+    snapshot: Option<HashMap<ServiceName, Vec<ServiceInstance>>>,
+}
+
+impl CacheUpdater {
+    pub(crate) fn new(cache: InstanceCache, names: Vec<ServiceName>) -> Self {
+        let names: Vec<_> = names.iter().map(|name| normalize_name(name)).collect();
+        for name in &names {
+            cache.track(name);
+        }
+        Self {
+            cache,
+            names,
+            // a new subscription starts with a snapshot
+            snapshot: Some(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn apply(&mut self, event: ServiceEvent) {
+        let kind = event.kind();
+        match (kind, event.instance) {
+            (EventKind::Resync, _) => self.snapshot = Some(HashMap::new()),
+            (EventKind::SnapshotEnd, _) => {
+                if let Some(mut snapshot) = self.snapshot.take() {
+                    for name in &self.names {
+                        let instances = snapshot.remove(name).unwrap_or_default();
+                        self.cache.replace(name, instances);
+                    }
+                }
+            }
+            (_, Some(instance)) => match self.snapshot {
+                Some(ref mut snapshot) => {
+                    let instances = snapshot.entry(normalize_name(&instance.name)).or_default();
+                    apply(instances, kind, instance);
+                }
+                None => self.cache.update(kind, instance),
+            },
+            (_, None) => {}
+        }
+    }
+}
+
+/// returns whether the instances changed
+fn apply(instances: &mut Vec<ServiceInstance>, kind: EventKind, instance: ServiceInstance) -> bool {
+    let position = instances.iter().position(|i| i.id == instance.id);
+    match (kind, position) {
+        (EventKind::Removed, Some(position)) => {
+            instances.remove(position);
+            true
+        }
+        (EventKind::Removed, None) => false,
+        (_, Some(position)) => {
+            instances[position] = instance;
+            true
+        }
+        (_, None) => {
+            instances.push(instance);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: EventKind, id: &str, port: i32, revision: u64) -> ServiceEvent {
+        let instance = ServiceInstance {
+            id: id.to_string(),
+            name: "api".to_string(),
+            port,
+            ..Default::default()
+        };
+        ServiceEvent::new(kind, instance, revision)
+    }
+
+    fn ports(cache: &InstanceCache) -> Vec<i32> {
+        let instances = cache.instances("api").unwrap();
+        instances.iter().map(|i| i.port).collect()
+    }
+
+    #[test]
+    fn test_cache_updater() {
+        let cache = InstanceCache::default();
+        let mut updater = CacheUpdater::new(cache.clone(), vec!["api".to_string()]);
+        assert_eq!(cache.instances("api"), Some(vec![]));
+        assert_eq!(cache.instances("web"), None);
+
+        updater.apply(event(EventKind::Added, "1", 8080, 1));
+        assert!(ports(&cache).is_empty());
+        updater.apply(ServiceEvent::snapshot_end(1));
+        assert_eq!(ports(&cache), vec![8080]);
+
+        let changes = cache.changes("api").unwrap();
+        updater.apply(event(EventKind::Added, "2", 8081, 2));
+        updater.apply(event(EventKind::Updated, "1", 8082, 3));
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(ports(&cache), vec![8082, 8081]);
+
+        // the previous instances are served until the snapshot is complete
+        updater.apply(ServiceEvent::resync(5));
+        updater.apply(event(EventKind::Added, "2", 8081, 5));
+        assert_eq!(ports(&cache), vec![8082, 8081]);
+        updater.apply(ServiceEvent::snapshot_end(5));
+        assert_eq!(ports(&cache), vec![8081]);
+
+        updater.apply(event(EventKind::Removed, "2", 8081, 6));
+        assert!(ports(&cache).is_empty());
+    }
+
+    #[test]
+    fn test_mixed_case_names() {
+        let cache = InstanceCache::default();
+        let mut updater = CacheUpdater::new(cache.clone(), vec![" API ".to_string()]);
+        let changes = cache.changes("Api").unwrap();
+
+        updater.apply(event(EventKind::Added, "1", 8080, 1));
+        updater.apply(ServiceEvent::snapshot_end(1));
+        assert_eq!(ports(&cache), vec![8080]);
+        assert_eq!(cache.instances("API").unwrap().len(), 1);
+        assert!(changes.has_changed().unwrap());
+    }
+}
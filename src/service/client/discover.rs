@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use tokio::sync::mpsc::Receiver;
+use tonic::transport::{ClientTlsConfig, Endpoint};
+use tower::discover::Change;
+use tracing::error;
+
+use crate::service::hub::ServiceId;
+use crate::service::{EventKind, Scheme, ServiceEvent, ServiceInstance, ServiceStatus};
+
+/// a [`tower::discover::Discover`] of the endpoints of a service, inserting and
+/// removing endpoints as its instances come and go.
+///
+/// only the instances which are up are inserted, an instance going down is
+/// removed until it is up again. the instances served over https are only
+/// inserted with a tls configuration, see [`ServiceDiscover::tls_config`].
+pub struct ServiceDiscover {
+    events: Receiver<ServiceEvent>,
+    tls_config: Option<ClientTlsConfig>,
+    // the uri of every inserted endpoint
+    endpoints: HashMap<ServiceId, String>,
+    // the instances seen since a resync
+    snapshot: Option<HashSet<ServiceId>>,
+    changes: VecDeque<Change<ServiceId, Endpoint>>,
+}
+
+impl ServiceDiscover {
+    pub fn new(events: Receiver<ServiceEvent>) -> Self {
+        Self {
+            events,
+            tls_config: None,
+            endpoints: HashMap::new(),
+            snapshot: None,
+            changes: VecDeque::new(),
+        }
+    }
+
+    /// the tls configuration of the connections to the instances served over
+    /// https, the certificate is checked against the address of the instance
+    pub fn tls_config(mut self, tls_config: ClientTlsConfig) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    fn endpoint(&self, instance: &ServiceInstance, uri: String) -> Result<Endpoint, String> {
+        let endpoint = Endpoint::from_shared(uri).map_err(|e| e.to_string())?;
+        if instance.scheme() != Scheme::Https {
+            return Ok(endpoint);
+        }
+        let Some(ref config) = self.tls_config else {
+            return Err("no tls configuration for an https instance".to_string());
+        };
+        let config = match instance.address.parse::<IpAddr>() {
+            Ok(_) => config.clone(),
+            Err(_) => config.clone().domain_name(instance.address.clone()),
+        };
+        endpoint.tls_config(config).map_err(|e| e.to_string())
+    }
+
+    fn apply(&mut self, event: ServiceEvent) {
+        let kind = event.kind();
+        match (kind, event.instance) {
+            (EventKind::Resync, _) => self.snapshot = Some(HashSet::new()),
+            (EventKind::SnapshotEnd, _) => {
+                if let Some(seen) = self.snapshot.take() {
+                    let gone: Vec<_> = self
+                        .endpoints
+                        .keys()
+                        .filter(|id| !seen.contains(*id))
+                        .cloned()
+                        .collect();
+                    for id in gone {
+                        self.remove(id);
+                    }
+                }
+            }
+            (EventKind::Removed, Some(instance)) => self.remove(instance.id),
+            (_, Some(instance)) if instance.status() == ServiceStatus::Down => {
+                self.remove(instance.id)
+            }
+            (_, Some(instance)) => {
+                if let Some(ref mut seen) = self.snapshot {
+                    seen.insert(instance.id.clone());
+                }
+                self.insert(instance);
+            }
+            (_, None) => {}
+        }
+    }
+
+    fn insert(&mut self, instance: ServiceInstance) {
+        let uri = format!(
+            "{}://{}:{}",
+            instance.scheme(),
+            instance.address,
+            instance.port
+        );
+        // an update which does not move the instance keeps its connection
+        if self.endpoints.get(&instance.id) == Some(&uri) {
+            return;
+        }
+        match self.endpoint(&instance, uri.clone()) {
+            Ok(endpoint) => {
+                self.endpoints.insert(instance.id.clone(), uri);
+                self.changes
+                    .push_back(Change::Insert(instance.id, endpoint));
+            }
+            Err(e) => {
+                error!(
+                    "Invalid endpoint {} of instance {}: {}",
+                    uri, instance.id, e
+                );
+                self.remove(instance.id);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: ServiceId) {
+        if self.endpoints.remove(&id).is_some() {
+            self.changes.push_back(Change::Remove(id));
+        }
+    }
+}
+
+impl Stream for ServiceDiscover {
+    type Item = Result<Change<ServiceId, Endpoint>, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(change) = self.changes.pop_front() {
+                return Poll::Ready(Some(Ok(change)));
+            }
+            match self.events.poll_recv(cx) {
+                Poll::Ready(Some(event)) => self.apply(event),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn event(kind: EventKind, id: &str, port: i32, status: ServiceStatus) -> ServiceEvent {
+        let instance = ServiceInstance {
+            scheme: Scheme::Http as i32,
+            id: id.to_string(),
+            name: "api".to_string(),
+            address: "127.0.0.1".to_string(),
+            port,
+            status: status as i32,
+            ..Default::default()
+        };
+        ServiceEvent::new(kind, instance, 0)
+    }
+
+    fn describe(change: Change<ServiceId, Endpoint>) -> String {
+        match change {
+            Change::Insert(id, endpoint) => format!("+{} {}", id, endpoint.uri()),
+            Change::Remove(id) => format!("-{}", id),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service_discover() {
+        use ServiceStatus::{Down, Up};
+
+        let (tx, rx) = mpsc::channel(16);
+        for event in [
+            event(EventKind::Added, "1", 8080, Up),
+            event(EventKind::Added, "2", 8081, Up),
+            ServiceEvent::snapshot_end(2),
+            // metadata changes keep the connection
+            event(EventKind::Updated, "1", 8080, Up),
+            event(EventKind::Updated, "1", 8082, Up),
+            event(EventKind::StatusChanged, "2", 8081, Down),
+            event(EventKind::StatusChanged, "2", 8081, Up),
+            ServiceEvent::resync(9),
+            event(EventKind::Added, "2", 8081, Up),
+            ServiceEvent::snapshot_end(9),
+        ] {
+            tx.send(event).await.unwrap();
+        }
+        drop(tx);
+
+        let changes: Vec<_> = ServiceDiscover::new(rx)
+            .map(|change| describe(change.unwrap()))
+            .collect()
+            .await;
+        assert_eq!(
+            changes,
+            vec![
+                "+1 http://127.0.0.1:8080/",
+                "+2 http://127.0.0.1:8081/",
+                "+1 http://127.0.0.1:8082/",
+                "-2",
+                "+2 http://127.0.0.1:8081/",
+                "-1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_https_instances() {
+        let mut https = event(EventKind::Added, "1", 8443, ServiceStatus::Up);
+        https.instance.as_mut().unwrap().scheme = Scheme::Https as i32;
+
+        // never connected over plaintext
+        let (tx, rx) = mpsc::channel(16);
+        tx.send(https.clone()).await.unwrap();
+        drop(tx);
+        let changes: Vec<_> = ServiceDiscover::new(rx).collect().await;
+        assert!(changes.is_empty());
+
+        let (tx, rx) = mpsc::channel(16);
+        tx.send(https).await.unwrap();
+        drop(tx);
+        let changes: Vec<_> = ServiceDiscover::new(rx)
+            .tls_config(ClientTlsConfig::new())
+            .map(|change| describe(change.unwrap()))
+            .collect()
+            .await;
+        assert_eq!(changes, vec!["+1 https://127.0.0.1:8443/"]);
+    }
+}
@@ -0,0 +1,271 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use futures::future;
+use tokio::net::lookup_host;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::time;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::Code;
+use tower::discover::Change;
+use tracing::{debug, warn};
+
+use crate::error::SynapseError;
+use crate::health::{HealthCheckRequest, HealthClient};
+use crate::service::{Scheme, ServingStatus};
+
+/// how the client connects to every synapse server
+#[derive(Debug, Clone)]
+pub(crate) struct EndpointConfig {
+    pub(crate) scheme: Scheme,
+    pub(crate) tls_config: Option<ClientTlsConfig>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) connect_timeout: Option<Duration>,
+}
+
+impl EndpointConfig {
+    fn endpoint(&self, host: &str, addr: SocketAddr) -> Result<Endpoint, tonic::transport::Error> {
+        let mut endpoint = Endpoint::from_shared(format!("{}://{}", self.scheme, addr))?;
+
+        if let Some(ref config) = self.tls_config {
+            // the certificate is checked against the name of the server rather
+            // than its resolved address
+            let config = match host.parse::<IpAddr>() {
+                Ok(_) => config.clone(),
+                Err(_) => config.clone().domain_name(host),
+            };
+            endpoint = endpoint.tls_config(config)?;
+        }
+
+        if let Some(timeout) = self.timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            endpoint = endpoint.connect_timeout(connect_timeout);
+        }
+        Ok(endpoint)
+    }
+}
+
+/// the longest a probe waits for the health of a server when the client has
+/// no timeout
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the synapse servers of a client, a name resolving to several addresses
+/// counts as several servers.
+///
+/// the client talks to a single server at a time, the first healthy one in the
+/// order they were given. the servers are probed with the grpc health check,
+/// the client fails over to the next healthy server only once the active one
+/// fails, and stays there.
+pub(crate) struct Servers {
+    config: EndpointConfig,
+    servers: Vec<(String, u16)>,
+    active: watch::Sender<Option<SocketAddr>>,
+    tx: Sender<Change<SocketAddr, Endpoint>>,
+}
+
+impl Servers {
+    /// the channel to the active server, probed every interval, and a receiver
+    /// notified every time the client fails over to another server
+    pub(crate) async fn failover(
+        config: EndpointConfig,
+        servers: Vec<(String, u16)>,
+        interval: Duration,
+    ) -> Result<(Channel, watch::Receiver<Option<SocketAddr>>), SynapseError> {
+        let (channel, tx) = Channel::balance_channel(16);
+        let (active, rx) = watch::channel(None);
+        let mut servers = Servers {
+            config,
+            servers,
+            active,
+            tx,
+        };
+        servers.probe().await;
+        if servers.active().is_none() {
+            return Err(SynapseError::Connection(
+                "No synapse server available".to_string(),
+            ));
+        }
+
+        tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                if servers.tx.is_closed() {
+                    // the client was dropped
+                    return;
+                }
+                servers.probe().await;
+            }
+        });
+        Ok((channel, rx))
+    }
+
+    fn active(&self) -> Option<SocketAddr> {
+        *self.active.borrow()
+    }
+
+    async fn probe(&mut self) {
+        let mut endpoints = Vec::new();
+        for (host, port) in &self.servers {
+            match lookup_host((host.as_str(), *port)).await {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        match self.config.endpoint(host, addr) {
+                            Ok(endpoint) => endpoints.push((addr, endpoint)),
+                            Err(e) => warn!("Invalid synapse server {}: {}", addr, e),
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to resolve synapse server {}: {}", host, e),
+            }
+        }
+
+        let timeout = self.config.timeout.unwrap_or(PROBE_TIMEOUT);
+        let active = self.active();
+        // the active server stays while it is healthy
+        if let Some((_, endpoint)) = endpoints.iter().find(|(addr, _)| Some(*addr) == active) {
+            if check(endpoint.clone(), timeout).await {
+                return;
+            }
+        }
+
+        let checks = endpoints
+            .iter()
+            .filter(|(addr, _)| Some(*addr) != active)
+            .map(|(addr, endpoint)| async move { (*addr, check(endpoint.clone(), timeout).await) });
+        let checks = future::join_all(checks).await;
+        let next = checks
+            .into_iter()
+            .find_map(|(addr, healthy)| healthy.then_some(addr));
+        let mut changes = Vec::new();
+        if let Some(active) = active {
+            warn!("Synapse server {} is down", active);
+            changes.push(Change::Remove(active));
+        }
+        if let Some(next) = next {
+            debug!("Connecting to synapse server {}", next);
+            let (_, endpoint) = endpoints
+                .into_iter()
+                .find(|(addr, _)| *addr == next)
+                .unwrap();
+            changes.push(Change::Insert(next, endpoint));
+        }
+        if next != active {
+            self.active.send_replace(next);
+        }
+
+        for change in changes {
+            if self.tx.send(change).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// a server without the health service is healthy once connected
+async fn check(endpoint: Endpoint, timeout: Duration) -> bool {
+    let check = async {
+        let Ok(channel) = endpoint.connect().await else {
+            return false;
+        };
+        let req = HealthCheckRequest {
+            service: String::new(),
+        };
+        match HealthClient::new(channel).check(req).await {
+            Ok(res) => res.into_inner().status() == ServingStatus::Serving,
+            Err(status) => status.code() == Code::Unimplemented,
+        }
+    };
+    time::timeout(timeout, check).await.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+
+    use crate::health::{HealthServer, HealthService};
+
+    use super::*;
+
+    async fn serve() -> (u16, oneshot::Sender<()>, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let server = Server::builder()
+            .add_service(HealthServer::new(HealthService::new()))
+            .serve_with_incoming_shutdown(incoming, async {
+                stopped.await.ok();
+            });
+        let handle = tokio::spawn(async move {
+            server.await.unwrap();
+        });
+        (port, stop, handle)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let (primary, stop, server) = serve().await;
+        let (secondary, _stop, _server) = serve().await;
+        // nothing listens on a port freed right away
+        let down = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let config = EndpointConfig {
+            scheme: Scheme::Http,
+            tls_config: None,
+            timeout: None,
+            connect_timeout: Some(Duration::from_secs(1)),
+        };
+        let mut servers = Servers {
+            config,
+            servers: vec![
+                ("localhost".to_string(), down),
+                ("127.0.0.1".to_string(), primary),
+                ("127.0.0.1".to_string(), secondary),
+            ],
+            active: watch::channel(None).0,
+            tx,
+        };
+        // the first healthy server is the only one inserted
+        servers.probe().await;
+        assert_eq!(servers.active(), Some(addr(primary)));
+        assert!(
+            matches!(rx.try_recv(), Ok(Change::Insert(inserted, _)) if inserted == addr(primary))
+        );
+        assert!(rx.try_recv().is_err());
+        servers.probe().await;
+        assert!(rx.try_recv().is_err());
+
+        // the client fails over once the active server fails
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        servers.probe().await;
+        assert_eq!(servers.active(), Some(addr(secondary)));
+        assert!(matches!(rx.try_recv(), Ok(Change::Remove(removed)) if removed == addr(primary)));
+        assert!(
+            matches!(rx.try_recv(), Ok(Change::Insert(inserted, _)) if inserted == addr(secondary))
+        );
+
+        servers.servers.clear();
+        servers.probe().await;
+        assert_eq!(servers.active(), None);
+        assert!(matches!(rx.try_recv(), Ok(Change::Remove(removed)) if removed == addr(secondary)));
+    }
+}
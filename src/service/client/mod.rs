@@ -0,0 +1,573 @@
+mod balance;
+mod cache;
+mod discover;
+mod failover;
+mod registration;
+mod resume;
+mod retry;
+
+pub use balance::{
+    ConsistentHash, LeastOutstanding, LoadBalancer, Picked, PowerOfTwoChoices, Random, RoundRobin,
+    Weighted, MAX_WEIGHT, WEIGHT_METADATA,
+};
+pub(crate) use cache::CacheUpdater;
+pub use cache::InstanceCache;
+pub use discover::ServiceDiscover;
+pub use registration::Registration;
+pub use retry::{ConnectionState, RetryPolicy};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use futures::StreamExt;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::time;
+use tonic::transport::{Channel, ClientTlsConfig};
+use tracing::{debug, error, warn};
+
+use crate::error::SynapseError;
+use crate::service::client::failover::{EndpointConfig, Servers};
+use crate::service::client::resume::Resume;
+use crate::service::hub::{Namespace, ServiceId, ServiceName, DEFAULT_NAMESPACE};
+use crate::service::validation::normalize_name;
+use crate::service::{
+    QueryRequest, Scheme, Service, ServiceEvent, ServiceInstance, ServiceInstanceIdentifier,
+    ServiceRegistryClient, ServiceStatus, SubscribeRequest,
+};
+
+const DISCOVER_BUFFER_SIZE: usize = 64;
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct ServiceClient {
+    retry_policy: RetryPolicy,
+    state: Arc<watch::Sender<ConnectionState>>,
+    namespace: Namespace,
+    cache: InstanceCache,
+    balancers: Arc<DashMap<ServiceName, Arc<dyn LoadBalancer>>>,
+    instance_tls_config: Option<ClientTlsConfig>,
+    server: watch::Receiver<Option<SocketAddr>>,
+    client: ServiceRegistryClient<Channel>,
+}
+
+pub struct ServiceClientBuilder {
+    scheme: Scheme,
+    namespace: Namespace,
+    server_host: Option<String>,
+    server_port: Option<u16>,
+    servers: Vec<(String, u16)>,
+    health_check_interval: Duration,
+    tls_config: Option<ClientTlsConfig>,
+    instance_tls_config: Option<ClientTlsConfig>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    balancers: HashMap<ServiceName, Arc<dyn LoadBalancer>>,
+}
+
+impl Default for ServiceClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServiceClientBuilder {
+    pub fn new() -> Self {
+        Self {
+            scheme: Scheme::Http,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            server_host: None,
+            server_port: None,
+            servers: Vec::new(),
+            health_check_interval: HEALTH_CHECK_INTERVAL,
+            tls_config: None,
+            instance_tls_config: None,
+            timeout: None,
+            connect_timeout: None,
+            retry_policy: RetryPolicy::default(),
+            balancers: HashMap::new(),
+        }
+    }
+
+    pub fn server_host(mut self, host: impl Into<String>) -> Self {
+        self.server_host = Some(host.into());
+        self
+    }
+
+    pub fn server_port(mut self, port: u16) -> Self {
+        self.server_port = Some(port);
+        self
+    }
+
+    /// add a synapse server, the client talks to the first healthy server in
+    /// the order they were added and fails over to the next one when it fails.
+    /// a name resolving to several addresses adds each of them
+    pub fn server(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.servers.push((host.into(), port));
+        self
+    }
+
+    /// how often the synapse servers are probed with the grpc health check
+    pub fn health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// the namespace used by every request of the client
+    pub fn namespace(mut self, namespace: impl Into<Namespace>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    pub fn tls_config(mut self, tls_config: ClientTlsConfig) -> Self {
+        self.scheme = Scheme::Https;
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// the tls configuration of the connections to the instances served over
+    /// https by [`ServiceClient::channel`], the one of synapse by default
+    pub fn instance_tls_config(mut self, tls_config: ClientTlsConfig) -> Self {
+        self.instance_tls_config = Some(tls_config);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// how the subscriptions reconnect to synapse
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// the load balancer picking the instances of a service, round robin by
+    /// default
+    pub fn load_balancer(
+        mut self,
+        name: impl Into<ServiceName>,
+        balancer: impl LoadBalancer + 'static,
+    ) -> Self {
+        self.balancers
+            .insert(normalize_name(&name.into()), Arc::new(balancer));
+        self
+    }
+
+    pub async fn build(mut self) -> Result<ServiceClient, SynapseError> {
+        let mut servers = Vec::new();
+        if let Some(server_host) = self.server_host.take() {
+            let server_port = self
+                .server_port
+                .take()
+                .ok_or_else(|| SynapseError::InvalidArgument("Server port not set".to_string()))?;
+            servers.push((server_host, server_port));
+        }
+        servers.append(&mut self.servers);
+        if servers.is_empty() {
+            return Err(SynapseError::InvalidArgument(
+                "Server host not set".to_string(),
+            ));
+        }
+        debug!("Building endpoints, connecting to: {:?}", servers);
+
+        let instance_tls_config = self.instance_tls_config.or_else(|| self.tls_config.clone());
+        let config = EndpointConfig {
+            scheme: self.scheme,
+            tls_config: self.tls_config,
+            timeout: self.timeout,
+            connect_timeout: self.connect_timeout,
+        };
+        let (channel, server) =
+            Servers::failover(config, servers, self.health_check_interval).await?;
+        let client = ServiceRegistryClient::new(channel);
+
+        Ok(ServiceClient {
+            retry_policy: self.retry_policy,
+            state: Arc::new(watch::channel(ConnectionState::Connected).0),
+            namespace: self.namespace,
+            cache: InstanceCache::default(),
+            balancers: Arc::new(self.balancers.into_iter().collect()),
+            instance_tls_config,
+            server,
+            client,
+        })
+    }
+}
+
+impl ServiceClient {
+    pub fn builder() -> ServiceClientBuilder {
+        ServiceClientBuilder::new()
+    }
+
+    /// subscribe to a service by name, or to several services, a prefix or the
+    /// whole catalog with a [`SubscribeRequest`]
+    pub async fn subscribe(
+        &mut self,
+        req: impl Into<SubscribeRequest>,
+    ) -> Result<Receiver<ServiceEvent>, SynapseError> {
+        let mut req = req.into();
+        if req.namespace.is_empty() {
+            req.namespace.clone_from(&self.namespace);
+        }
+        debug!("Subscribe to service: {:?}", req);
+
+        let (tx, rx) = tokio::sync::mpsc::channel(256);
+
+        // spawn a task to subscribe to the service
+        self.handle_service(tx, req).await;
+        Ok(rx)
+    }
+
+    /// watch services and keep their instances in the local cache of the
+    /// client, read with [`ServiceClient::instances`]
+    pub async fn watch<I, S>(&mut self, names: I) -> Result<(), SynapseError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<ServiceName>,
+    {
+        let names: Vec<ServiceName> = names
+            .into_iter()
+            .map(|name| normalize_name(&name.into()))
+            .collect();
+        let mut updater = CacheUpdater::new(self.cache.clone(), names.clone());
+        let mut rx = self
+            .subscribe(SubscribeRequest::for_services(names))
+            .await?;
+
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                updater.apply(event);
+            }
+        });
+        Ok(())
+    }
+
+    /// discover the endpoints of a service, see [`ServiceDiscover`]; the
+    /// instances served over https are connected with the instance tls
+    /// configuration of the builder, and left out without one
+    pub async fn discover(
+        &mut self,
+        name: impl Into<ServiceName>,
+    ) -> Result<ServiceDiscover, SynapseError> {
+        let rx = self.subscribe(SubscribeRequest::new(name.into())).await?;
+        let discover = ServiceDiscover::new(rx);
+        Ok(match self.instance_tls_config.clone() {
+            Some(tls_config) => discover.tls_config(tls_config),
+            None => discover,
+        })
+    }
+
+    /// a [`Channel`] balanced over the instances of a service, following them
+    /// as they come and go
+    pub async fn channel(&mut self, name: impl Into<ServiceName>) -> Result<Channel, SynapseError> {
+        let mut discover = self.discover(name).await?;
+        let (channel, tx) = Channel::balance_channel(DISCOVER_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            while let Some(Ok(change)) = discover.next().await {
+                if tx.send(change).await.is_err() {
+                    // the channel was dropped
+                    break;
+                }
+            }
+        });
+        Ok(channel)
+    }
+
+    /// the cached instances of a watched service, `None` if it is not watched.
+    ///
+    /// the last known instances are returned when synapse is unreachable.
+    pub fn instances(&self, name: &str) -> Option<Vec<ServiceInstance>> {
+        self.cache.instances(name)
+    }
+
+    /// pick an instance of a watched service which is up with the load balancer
+    /// of the service, `key` is the request key used by [`ConsistentHash`]
+    pub fn pick(&self, name: &str, key: Option<&str>) -> Option<Picked> {
+        let mut instances: Vec<_> = self
+            .cache
+            .instances(name)?
+            .into_iter()
+            .filter(|instance| instance.status() == ServiceStatus::Up)
+            .collect();
+        let balancer = self
+            .balancers
+            .entry(normalize_name(name))
+            .or_insert_with(|| Arc::new(RoundRobin::default()))
+            .clone();
+        let index = balancer.pick(&instances, key)?;
+        Some(Picked::new(instances.swap_remove(index), balancer))
+    }
+
+    /// a receiver notified every time the cached instances of a watched service
+    /// change
+    pub fn changes(&self, name: &str) -> Option<watch::Receiver<Vec<ServiceInstance>>> {
+        self.cache.changes(name)
+    }
+
+    /// the synapse server the client talks to, None while every server is down
+    pub fn server(&self) -> Option<SocketAddr> {
+        *self.server.borrow()
+    }
+
+    /// the state of the connection of the subscriptions to synapse, notified
+    /// on every change
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    async fn handle_service(&self, tx: Sender<ServiceEvent>, mut req: SubscribeRequest) {
+        let mut backoff = self.retry_policy.backoff();
+        let state = self.state.clone();
+        let mut client = self.client.clone();
+        let mut server = self.server.clone();
+
+        tokio::spawn(async move {
+            let mut resume = Resume::default();
+            // the server of the last stream
+            let mut subscribed_to = None;
+            loop {
+                let active = *server.borrow_and_update();
+                if subscribed_to.is_some() && subscribed_to != active {
+                    // the revisions of another server do not apply
+                    Resume::restart(&mut req);
+                }
+                // subscribe to the service
+                match client.subscribe_to_service(req.clone()).await {
+                    Ok(stream) => {
+                        backoff.reset();
+                        subscribed_to = active;
+                        if let Some(resync) = resume.opened(&req) {
+                            // the receiver drops what it saw of the previous streams
+                            if tx.send(resync).await.is_err() {
+                                return;
+                            }
+                        }
+                        state.send_if_modified(|state| {
+                            let changed = *state != ConnectionState::Connected;
+                            *state = ConnectionState::Connected;
+                            changed
+                        });
+                        let mut stream = stream.into_inner();
+                        loop {
+                            let message = tokio::select! {
+                                // the receiver was dropped
+                                _ = tx.closed() => return,
+                                Ok(()) = server.changed() => {
+                                    warn!("Synapse server changed, subscribing again");
+                                    break;
+                                }
+                                message = stream.message() => message,
+                            };
+                            match message {
+                                Ok(Some(res)) => {
+                                    debug!("GOT = {:?}", res);
+                                    // resume from the last revision after a reconnection
+                                    resume.forward(&mut req, &res);
+                                    if tx.send(res).await.is_err() {
+                                        return;
+                                    }
+                                }
+                                Ok(None) => {
+                                    warn!("Subscription closed by the server");
+                                    break;
+                                }
+                                Err(e) => {
+                                    error!("Subscription failed: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                        resume.ended(&mut req);
+                    }
+                    Err(e) => error!("Failed to subscribe to service: {}", e),
+                }
+
+                match backoff.next_delay() {
+                    Some(delay) => {
+                        let attempt = backoff.attempt();
+                        debug!(
+                            "Attempting to reconnect in {:?}... Attempt: {}",
+                            delay, attempt
+                        );
+                        state.send_replace(ConnectionState::Reconnecting {
+                            attempt,
+                            backoff: delay,
+                        });
+                        time::sleep(delay).await;
+                    }
+                    None => {
+                        error!("Retry policy exhausted, giving up.");
+                        state.send_replace(ConnectionState::Disconnected);
+                        // exit the task, and it will close the channel
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    pub async fn register(&mut self, mut instance: ServiceInstance) -> Result<(), SynapseError> {
+        if instance.namespace.is_empty() {
+            instance.namespace.clone_from(&self.namespace);
+        }
+        debug!("Register service instance: {:?}", instance);
+
+        let res = self.client.register_service(instance).await?;
+        if !res.into_inner().success {
+            return Err(SynapseError::Internal(
+                "Failed to register service instance".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// register an instance and keep it registered with a heartbeat until the
+    /// returned [`Registration`] is dropped
+    pub async fn registration(
+        &self,
+        mut instance: ServiceInstance,
+        heartbeat: Duration,
+    ) -> Result<Registration, SynapseError> {
+        if instance.namespace.is_empty() {
+            instance.namespace.clone_from(&self.namespace);
+        }
+        let mut client = self.clone();
+        client.register(instance.clone()).await?;
+        Ok(Registration::new(client, instance, heartbeat))
+    }
+
+    pub async fn unregister(
+        &mut self,
+        name: ServiceName,
+        id: ServiceId,
+    ) -> Result<(), SynapseError> {
+        let namespace = self.namespace.clone();
+        self.unregister_in_namespace(namespace, name, id).await
+    }
+
+    /// unregister an instance registered in another namespace than the one of
+    /// the client
+    pub async fn unregister_in_namespace(
+        &mut self,
+        namespace: Namespace,
+        name: ServiceName,
+        id: ServiceId,
+    ) -> Result<(), SynapseError> {
+        debug!(
+            "Unregister service instance -- namespace: {:?}; -- name: {:?}; -- id: {:?}",
+            namespace, name, id
+        );
+
+        let res = self
+            .client
+            .unregister_service(ServiceInstanceIdentifier::new(name, id).with_namespace(&namespace))
+            .await?;
+
+        if !res.into_inner().success {
+            return Err(SynapseError::Internal(
+                "Failed to unregister service instance".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn query_with_name(
+        &mut self,
+        name: impl AsRef<str>,
+    ) -> Result<Vec<Service>, SynapseError> {
+        debug!("Query service: {}", name.as_ref());
+
+        let res = self
+            .client
+            .query_services(
+                QueryRequest::new(name.as_ref().to_string()).with_namespace(&self.namespace),
+            )
+            .await?;
+        Ok(res.into_inner().services)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::Request;
+
+    use crate::service::client::{ServiceClient, Weighted, WEIGHT_METADATA};
+    use crate::service::hub::Hub;
+    use crate::service::{ServiceInstance, ServiceRegistry, ServiceRegistryServer};
+
+    #[tokio::test]
+    async fn test_new() {
+        let mut client = ServiceClient::builder()
+            .server_host("127.0.0.1")
+            .server_port(8500)
+            .build()
+            .await
+            .unwrap();
+        let mut receiver = client.subscribe("test").await.unwrap();
+        while let Some(client) = receiver.recv().await {
+            println!("Received:{:?}", client);
+        }
+        println!("{:?}", client)
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_names() {
+        let hub = Hub::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let server = Server::builder()
+            .add_service(ServiceRegistryServer::new(hub.clone()))
+            .serve_with_incoming(incoming);
+        tokio::spawn(server);
+        for (id, weight) in [("1", "0"), ("2", "1")] {
+            let mut instance = ServiceInstance {
+                id: id.to_string(),
+                name: "api".to_string(),
+                address: "127.0.0.1".to_string(),
+                port: 8080,
+                ..Default::default()
+            };
+            instance
+                .metadata
+                .insert(WEIGHT_METADATA.to_string(), weight.to_string());
+            hub.register_service(Request::new(instance)).await.unwrap();
+        }
+
+        let mut client = ServiceClient::builder()
+            .server_host("127.0.0.1")
+            .server_port(port)
+            .load_balancer("API", Weighted)
+            .build()
+            .await
+            .unwrap();
+        client.watch(["Api"]).await.unwrap();
+        let mut changes = client.changes("api").unwrap();
+        changes
+            .wait_for(|instances| instances.len() == 2)
+            .await
+            .unwrap();
+
+        assert_eq!(client.instances(" API ").unwrap().len(), 2);
+        // the weighted balancer of the service never picks a weight of 0
+        for _ in 0..10 {
+            assert_eq!(client.pick("aPi", None).unwrap().id, "2");
+        }
+    }
+}
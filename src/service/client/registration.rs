@@ -0,0 +1,223 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{debug, error, warn};
+
+use crate::error::SynapseError;
+use crate::service::client::ServiceClient;
+use crate::service::ServiceInstance;
+
+/// an instance kept registered by [`ServiceClient::registration`].
+///
+/// the instance is registered again at every heartbeat, which restores it
+/// after synapse restarts without touching the status found by its health
+/// check, and deregistered when the handle is dropped or the
+/// signal given to [`Registration::until`] completes.
+#[derive(Debug)]
+pub struct Registration {
+    client: ServiceClient,
+    instance: ServiceInstance,
+    heartbeat: JoinHandle<()>,
+    registered: bool,
+}
+
+impl Registration {
+    pub(crate) fn new(
+        client: ServiceClient,
+        instance: ServiceInstance,
+        interval: Duration,
+    ) -> Self {
+        let mut heartbeat_client = client.clone();
+        let heartbeat_instance = instance.clone();
+        let heartbeat = tokio::spawn(async move {
+            let mut interval = time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // the first tick completes immediately, the instance is registered
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let result = heartbeat_client.register(heartbeat_instance.clone()).await;
+                if let Err(e) = result {
+                    warn!("Failed to renew the registration: {}", e);
+                }
+            }
+        });
+        Self {
+            client,
+            instance,
+            heartbeat,
+            registered: true,
+        }
+    }
+
+    pub fn instance(&self) -> &ServiceInstance {
+        &self.instance
+    }
+
+    /// stop the heartbeat and deregister the instance
+    pub async fn deregister(mut self) -> Result<(), SynapseError> {
+        self.heartbeat.abort();
+        self.registered = false;
+        let ServiceInstance {
+            namespace,
+            name,
+            id,
+            ..
+        } = self.instance.clone();
+        self.client
+            .unregister_in_namespace(namespace, name, id)
+            .await
+    }
+
+    /// deregister the instance once the signal completes, to be used as the
+    /// shutdown signal of a tonic or axum server:
+    ///
+    /// ```ignore
+    /// Server::builder()
+    ///     .add_service(service)
+    ///     .serve_with_shutdown(addr, registration.until(shutdown_signal()))
+    /// ```
+    pub async fn until(self, signal: impl Future<Output = ()>) {
+        signal.await;
+        debug!("Deregister service instance: {:?}", self.instance);
+        if let Err(e) = self.deregister().await {
+            error!("Failed to deregister service instance: {}", e);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+        if !self.registered {
+            return;
+        }
+        // the runtime is gone when dropped after shutdown
+        if let Ok(handle) = Handle::try_current() {
+            let mut client = self.client.clone();
+            let ServiceInstance {
+                namespace,
+                name,
+                id,
+                ..
+            } = self.instance.clone();
+            handle.spawn(async move {
+                if let Err(e) = client.unregister_in_namespace(namespace, name, id).await {
+                    error!("Failed to deregister service instance: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+    use tokio::time;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::Request;
+
+    use crate::service::client::ServiceClient;
+    use crate::service::hub::{Hub, ServiceKey};
+    use crate::service::{
+        ServiceInstance, ServiceInstanceIdentifier, ServiceRegistry, ServiceRegistryServer,
+    };
+
+    #[tokio::test]
+    async fn test_registration() {
+        let hub = Hub::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let server = Server::builder()
+            .add_service(ServiceRegistryServer::new(hub.clone()))
+            .serve_with_incoming(incoming);
+        tokio::spawn(server);
+
+        let client = ServiceClient::builder()
+            .server_host("127.0.0.1")
+            .server_port(port)
+            .build()
+            .await
+            .unwrap();
+        let instance = ServiceInstance {
+            id: "1".to_string(),
+            name: "api".to_string(),
+            address: "127.0.0.1".to_string(),
+            port: 8080,
+            ..Default::default()
+        };
+        let key = ServiceKey::new("", "api");
+        let registration = client
+            .registration(instance, Duration::from_millis(20))
+            .await
+            .unwrap();
+        assert_eq!(hub.instances(&key).len(), 1);
+
+        // the heartbeat restores the instance and is not broadcast
+        let revision = hub.revision();
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(hub.revision(), revision);
+        let identifier = ServiceInstanceIdentifier::new("api".to_string(), "1".to_string());
+        hub.unregister_service(Request::new(identifier))
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(hub.instances(&key).len(), 1);
+
+        drop(registration);
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(hub.instances(&key).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_registration_in_other_namespace() {
+        let hub = Hub::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let server = Server::builder()
+            .add_service(ServiceRegistryServer::new(hub.clone()))
+            .serve_with_incoming(incoming);
+        tokio::spawn(server);
+
+        let client = ServiceClient::builder()
+            .server_host("127.0.0.1")
+            .server_port(port)
+            .namespace("team-a")
+            .build()
+            .await
+            .unwrap();
+        let instance = ServiceInstance {
+            id: "1".to_string(),
+            name: "api".to_string(),
+            address: "127.0.0.1".to_string(),
+            port: 8080,
+            namespace: "team-b".to_string(),
+            ..Default::default()
+        };
+        let key = ServiceKey::new("team-b", "api");
+        let registration = client
+            .registration(instance.clone(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(hub.instances(&key).len(), 1);
+        registration.deregister().await.unwrap();
+        assert!(hub.instances(&key).is_empty());
+
+        let registration = client
+            .registration(instance, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(hub.instances(&key).len(), 1);
+        drop(registration);
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(hub.instances(&key).is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::service::{EventKind, ServiceEvent, SubscribeRequest};
+
+/// where a subscription resumes from after a reconnection.
+///
+/// the revision only moves with the events following a complete snapshot, a
+/// stream ending before its SNAPSHOT_END resumes from a fresh snapshot.
+#[derive(Debug, Default)]
+pub(crate) struct Resume {
+    /// the current stream sent its SNAPSHOT_END
+    synced: bool,
+    /// events were forwarded, the receiver has a view to drop before a fresh
+    /// snapshot
+    forwarded: bool,
+}
+
+impl Resume {
+    /// a stream was opened with the request, returns the RESYNC the receiver
+    /// needs if the stream starts over from a fresh snapshot
+    pub(crate) fn opened(&mut self, req: &SubscribeRequest) -> Option<ServiceEvent> {
+        self.synced = false;
+        (req.resume_from == 0 && self.forwarded).then(|| ServiceEvent::resync(0))
+    }
+
+    /// an event of the stream is forwarded to the receiver
+    pub(crate) fn forward(&mut self, req: &mut SubscribeRequest, event: &ServiceEvent) {
+        self.forwarded = true;
+        match event.kind() {
+            // a snapshot follows
+            EventKind::Resync => self.synced = false,
+            EventKind::SnapshotEnd => self.synced = true,
+            _ => {}
+        }
+        if self.synced {
+            req.resume_from = req.resume_from.max(event.revision);
+            req.epoch = event.epoch;
+        }
+    }
+
+    /// the stream ended, a snapshot it did not complete is requested again
+    pub(crate) fn ended(&mut self, req: &mut SubscribeRequest) {
+        if !self.synced {
+            Self::restart(req);
+        }
+    }
+
+    /// start over from a fresh snapshot
+    pub(crate) fn restart(req: &mut SubscribeRequest) {
+        req.resume_from = 0;
+        req.epoch = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::service::ServiceInstance;
+
+    use super::*;
+
+    fn event(kind: EventKind, revision: u64) -> ServiceEvent {
+        let mut event = ServiceEvent::new(kind, ServiceInstance::default(), revision);
+        event.epoch = 7;
+        event
+    }
+
+    #[test]
+    fn test_disconnect_mid_snapshot() {
+        let mut resume = Resume::default();
+        let mut req = SubscribeRequest::new("api".to_string());
+        assert!(resume.opened(&req).is_none());
+
+        // the stream ends after a part of the snapshot
+        resume.forward(&mut req, &event(EventKind::Added, 5));
+        assert_eq!(req.resume_from, 0);
+        resume.ended(&mut req);
+        assert_eq!(req.resume_from, 0);
+
+        // the receiver drops the partial snapshot before the fresh one
+        let resync = resume.opened(&req).unwrap();
+        assert_eq!(resync.kind(), EventKind::Resync);
+        resume.forward(&mut req, &event(EventKind::Added, 5));
+        resume.forward(&mut req, &event(EventKind::Added, 5));
+        resume.forward(&mut req, &event(EventKind::SnapshotEnd, 5));
+        resume.forward(&mut req, &event(EventKind::Updated, 6));
+        assert_eq!((req.resume_from, req.epoch), (6, 7));
+        resume.ended(&mut req);
+        assert_eq!(req.resume_from, 6);
+
+        // resumed, the replay ends with a SNAPSHOT_END
+        assert!(resume.opened(&req).is_none());
+        resume.forward(&mut req, &event(EventKind::Removed, 7));
+        assert_eq!(req.resume_from, 6);
+        resume.forward(&mut req, &event(EventKind::SnapshotEnd, 7));
+        assert_eq!(req.resume_from, 7);
+
+        // resynced by the server, the stream ends before the snapshot is done
+        resume.forward(&mut req, &event(EventKind::Resync, 9));
+        resume.forward(&mut req, &event(EventKind::Added, 9));
+        resume.ended(&mut req);
+        assert_eq!((req.resume_from, req.epoch), (0, 0));
+        assert!(resume.opened(&req).is_some());
+    }
+}
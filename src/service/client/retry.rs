@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time::Instant;
+
+/// the state of the connection of the subscriptions of a
+/// [`ServiceClient`](super::ServiceClient) to synapse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// the connection was lost, the next attempt is made after the backoff
+    Reconnecting {
+        attempt: u32,
+        backoff: Duration,
+    },
+    /// the retry policy gave up, the subscriptions are closed
+    Disconnected,
+}
+
+/// how the subscriptions of a [`ServiceClient`](super::ServiceClient) reconnect
+/// to synapse: exponential backoff with jitter, unlimited by default
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_retries: Option<u32>,
+    max_elapsed_time: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_retries: None,
+            max_elapsed_time: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// the fraction of the backoff randomly added or removed, between 0 and 1
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// give up after this number of consecutive failed attempts
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = Some(retries);
+        self
+    }
+
+    /// give up once the connection has been lost for this long
+    pub fn max_elapsed_time(mut self, elapsed: Duration) -> Self {
+        self.max_elapsed_time = Some(elapsed);
+        self
+    }
+
+    pub(crate) fn backoff(&self) -> Backoff {
+        Backoff {
+            policy: self.clone(),
+            attempt: 0,
+            since: None,
+        }
+    }
+}
+
+/// the delays between the attempts of one connection, reset once connected
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    policy: RetryPolicy,
+    attempt: u32,
+    since: Option<Instant>,
+}
+
+impl Backoff {
+    pub(crate) fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.attempt = 0;
+        self.since = None;
+    }
+
+    /// the delay before the next attempt, `None` when the policy gives up
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        let policy = &self.policy;
+        let since = *self.since.get_or_insert_with(Instant::now);
+        if policy.max_retries.is_some_and(|max| self.attempt >= max)
+            || policy
+                .max_elapsed_time
+                .is_some_and(|max| since.elapsed() >= max)
+        {
+            return None;
+        }
+
+        let backoff = policy.initial_backoff.as_secs_f64()
+            * policy.multiplier.powi(self.attempt.min(64) as i32);
+        let backoff = backoff.min(policy.max_backoff.as_secs_f64());
+        let jitter = if policy.jitter > 0.0 {
+            rand::thread_rng().gen_range(-policy.jitter..=policy.jitter)
+        } else {
+            0.0
+        };
+        self.attempt += 1;
+        Some(Duration::from_secs_f64(backoff * (1.0 + jitter)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_millis(500))
+            .jitter(0.0)
+            .max_retries(4);
+        let mut backoff = policy.backoff();
+        let delays: Vec<_> = std::iter::from_fn(|| backoff.next_delay()).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 500].map(Duration::from_millis).to_vec()
+        );
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(100)));
+        assert_eq!(backoff.attempt(), 1);
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let mut backoff = RetryPolicy::default().jitter(0.5).backoff();
+        for _ in 0..100 {
+            let delay = backoff.next_delay().unwrap();
+            backoff.reset();
+            assert!((50..=150).contains(&delay.as_millis()));
+        }
+    }
+
+    #[test]
+    fn test_max_elapsed_time() {
+        let policy = RetryPolicy::default().max_elapsed_time(Duration::from_millis(10));
+        let mut backoff = policy.backoff();
+        assert!(backoff.next_delay().is_some());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(backoff.next_delay(), None);
+    }
+}
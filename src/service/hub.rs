@@ -4,32 +4,88 @@ use std::time::Duration;
 
 use async_trait::async_trait;
 use dashmap::DashMap;
-use futures::{Stream, StreamExt};
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::Sender;
-use tokio::time;
-use tonic::codegen::tokio_stream::wrappers::BroadcastStream;
+use futures::{stream, Stream};
+use tokio::task::{self, AbortHandle};
+use tokio::time::{self, Instant};
 use tonic::transport::{Channel, Endpoint};
 use tonic::{Request, Response, Status};
 use tracing::{debug, error, warn};
 
+use crate::error::SynapseError;
+use crate::metrics::METRICS;
 use crate::pb::health_client::HealthClient;
 use crate::pb::service_registry_server::ServiceRegistry;
 use crate::pb::{
-    HealthCheckRequest, OperationStatus, QueryRequest, QueryResponse, Service, ServiceInstance,
-    ServiceInstanceIdentifier, ServiceStatus, SubscribeRequest,
+    EventKind, HealthCheckRequest, OperationStatus, QueryRequest, QueryResponse, Service,
+    ServiceEvent, ServiceEventBatch, ServiceInstance, ServiceInstanceIdentifier, ServiceStatus,
+    SubscribeRequest,
 };
+use crate::service::subscription::{
+    self, ServiceSelector, SlowConsumerPolicy, Start, SubscriptionManager,
+};
+use crate::service::validation::{normalize_name, validate_instance};
+use crate::tls::{Authorize, Operation, PeerIdentity};
 
 pub type ServiceInstances = DashMap<ServiceId, ServiceInstance>;
 
 pub type ServiceName = String;
 
+pub type Namespace = String;
+
+/// namespace used when the request does not specify one
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// services are isolated by namespace, the same name in two namespaces
+/// refers to two different services
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ServiceKey {
+    pub namespace: Namespace,
+    pub name: ServiceName,
+}
+
+impl ServiceKey {
+    pub fn new(namespace: impl Into<Namespace>, name: impl Into<ServiceName>) -> Self {
+        let namespace = namespace.into();
+        Self {
+            namespace: if namespace.is_empty() {
+                DEFAULT_NAMESPACE.to_string()
+            } else {
+                normalize_name(&namespace)
+            },
+            name: normalize_name(&name.into()),
+        }
+    }
+}
+
 /// pb hub structure
 /// store the service information
-pub type RegistryPool = Arc<DashMap<ServiceName, ServiceInstances>>;
+pub type RegistryPool = Arc<DashMap<ServiceKey, ServiceInstances>>;
 
 pub type ServiceId = String;
-pub type PubSub = DashMap<ServiceName, Sender<Result<Service, Status>>>;
+
+#[derive(Clone, Debug)]
+pub struct HubConfig {
+    /// capacity of the event queue of each subscriber
+    pub channel_capacity: usize,
+    /// number of recent events kept to replay them to resuming subscribers
+    pub journal_capacity: usize,
+    /// what to do with a subscriber whose queue is full
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// authorizes the changes of the registry, every change is allowed when
+    /// none is given
+    pub authorizer: Option<Arc<dyn Authorize>>,
+}
+
+impl Default for HubConfig {
+    fn default() -> Self {
+        Self {
+            channel_capacity: 256,
+            journal_capacity: 1024,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            authorizer: None,
+        }
+    }
+}
 
 /// register center
 #[derive(Clone, Debug)]
@@ -37,14 +93,30 @@ pub struct Hub {
     /// register center
     registry_pool: RegistryPool,
     /// publish subscribe center
-    broadcaster: PubSub,
+    subscriptions: SubscriptionManager,
+    /// the health check task of each instance
+    health_checks: Arc<DashMap<(ServiceKey, ServiceId), AbortHandle>>,
+    authorizer: Option<Arc<dyn Authorize>>,
 }
 
 impl Hub {
     pub fn new() -> Self {
+        Self::with_config(HubConfig::default())
+    }
+
+    pub fn with_config(config: HubConfig) -> Self {
+        let registry_pool: RegistryPool = Arc::new(DashMap::new());
+        let subscriptions = SubscriptionManager::new(
+            registry_pool.clone(),
+            config.journal_capacity,
+            config.channel_capacity,
+            config.slow_consumer_policy,
+        );
         Self {
-            registry_pool: Arc::new(DashMap::new()),
-            broadcaster: DashMap::new(),
+            registry_pool,
+            subscriptions,
+            health_checks: Arc::new(DashMap::new()),
+            authorizer: config.authorizer,
         }
     }
 
@@ -55,15 +127,18 @@ impl Hub {
         max_tries: i32,
     ) -> (bool, bool) {
         let mut need_notify = false;
-        match pool.get(&i.name) {
+        match pool.get(&i.key()) {
             // service is unregistered
             None => (false, need_notify),
             Some(instances) => match instances.get_mut(&i.id) {
                 // service is unregistered
                 None => (false, need_notify),
                 Some(mut instance) => {
+                    // the instance was registered again without a health check
+                    let Some(mut health) = instance.health_check.clone() else {
+                        return (false, need_notify);
+                    };
                     // check the retries
-                    let mut health = instance.health_check.clone().unwrap();
                     if instance.status == ServiceStatus::Down as i32
                         && status == ServiceStatus::Down
                     {
@@ -91,6 +166,7 @@ impl Hub {
         }
     }
 
+    /// start the health check of the instance, replacing the running one
     pub fn health_check(&self, mut instance: ServiceInstance) {
         let addr = if cfg!(feature = "docker") && instance.address == "127.0.0.1" {
             format!(
@@ -107,48 +183,137 @@ impl Hub {
                 instance.port
             )
         };
-        let pool = self.registry_pool.clone();
-        let pub_sub = self.broadcaster.clone();
-        tokio::spawn(async move {
-            // open mod check
-            let health = instance.health_check.as_ref().unwrap();
-            let duration = Duration::from_secs(health.interval as u64);
-            debug!("health check start: {:?}", &instance);
-
-            let mut client = match Self::create_health_client(addr, health.timeout).await {
-                Ok(client) => client,
-                Err(err) => {
-                    // service mod check configuration error
-                    error!("create client failed: {:?}", err);
-                    return;
-                }
-            };
-            let req = HealthCheckRequest {
-                service: health.endpoint.clone(),
+        let hub = self.clone();
+        let task_key = (instance.key(), instance.id.clone());
+        let handle = tokio::spawn(async move {
+            hub.run_health_check(addr, &mut instance).await;
+            let id = task::id();
+            hub.health_checks
+                .remove_if(&(instance.key(), instance.id.clone()), |_, handle| {
+                    handle.id() == id
+                });
+        });
+        if let Some(running) = self.health_checks.insert(task_key, handle.abort_handle()) {
+            running.abort();
+        }
+    }
+
+    /// probe the instance until it is unregistered or it ran out of retries
+    async fn run_health_check(&self, addr: String, instance: &mut ServiceInstance) {
+        // open mod check
+        let health = instance.health_check.as_ref().unwrap();
+        let duration = Duration::from_secs(health.interval as u64);
+        debug!("health check start: {:?}", &instance);
+
+        let mut client = match Self::create_health_client(addr, health.timeout).await {
+            Ok(client) => client,
+            Err(err) => {
+                // service mod check configuration error
+                error!("create client failed: {:?}", err);
+                return;
+            }
+        };
+        let req = HealthCheckRequest {
+            service: health.endpoint.clone(),
+        };
+        let max_tries = health.retries;
+        let labels = [instance.namespace.clone(), instance.name.clone()];
+        loop {
+            time::sleep(duration).await;
+            let start = Instant::now();
+            let result = client.check(req.clone()).await;
+            METRICS
+                .health_probe_duration
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
+
+            let status = if result.is_ok() {
+                debug!("healt check success: {:?}", instance);
+                ServiceStatus::Up
+            } else {
+                warn!("healt check failed: {:?}", instance);
+                ServiceStatus::Down
             };
-            let max_tries = health.retries;
-            loop {
-                time::sleep(duration).await;
-                let result = client.check(req.clone()).await;
-
-                let status = if result.is_ok() {
-                    debug!("healt check success: {:?}", instance);
-                    ServiceStatus::Up
-                } else {
-                    warn!("healt check failed: {:?}", instance);
-                    ServiceStatus::Down
-                };
-
-                let (is_pass, need_notify) =
-                    Self::modify_service_status(&mut instance, status, &pool, max_tries);
-                if need_notify {
-                    Self::broadcast_(instance.clone(), &pub_sub).await;
-                }
-                if !is_pass {
-                    break;
-                }
+            let outcome = if result.is_ok() { "success" } else { "failure" };
+            METRICS
+                .health_probes
+                .with_label_values(&[&labels[0], &labels[1], outcome])
+                .inc();
+
+            let mut is_pass = false;
+            self.commit(|pool| {
+                let (pass, need_notify) =
+                    Self::modify_service_status(instance, status, pool, max_tries);
+                is_pass = pass;
+                need_notify.then(|| (EventKind::StatusChanged, instance.clone()))
+            });
+            if !is_pass {
+                break;
             }
+        }
+    }
+
+    /// restart the health check of a renewed instance if it gave up, the
+    /// status of the instance is kept until the next probe
+    fn renew_health_check(&self, renewed: &ServiceInstance) {
+        let Some(ref health) = renewed.health_check else {
+            return;
+        };
+        let key = (renewed.key(), renewed.id.clone());
+        if self
+            .health_checks
+            .get(&key)
+            .is_some_and(|running| !running.is_finished())
+        {
+            return;
+        }
+        let instance = self.registry_pool.get(&key.0).and_then(|instances| {
+            let mut instance = instances.get_mut(&key.1)?;
+            // the retries start over
+            instance.health_check = Some(health.clone());
+            Some(instance.clone())
         });
+        if let Some(instance) = instance {
+            self.health_check(instance);
+        }
+    }
+
+    /// check the change with the authorization hook, the identity of the
+    /// client is the [`PeerIdentity`] extension of its request
+    fn authorize(
+        &self,
+        identity: Option<&PeerIdentity>,
+        operation: Operation<'_>,
+    ) -> Result<(), SynapseError> {
+        if let Some(identity) = identity {
+            debug!("{:?} from {}", operation, identity.subject);
+        }
+        match self.authorizer {
+            Some(ref authorizer) => authorizer.authorize(identity, operation),
+            None => Ok(()),
+        }
+    }
+
+    /// whether the registration repeats the registered instance; the status
+    /// and the retries left of a health checked instance belong to its health
+    /// check, a heartbeat leaves them as they are
+    fn is_renewal(instance: &ServiceInstance, existing: &ServiceInstance) -> bool {
+        let mut existing = existing.clone();
+        if let (Some(health), Some(existing_health)) =
+            (&instance.health_check, &mut existing.health_check)
+        {
+            existing.status = instance.status;
+            existing_health.retries = health.retries;
+        }
+        instance == &existing
+    }
+
+    /// number of running health checks
+    pub fn health_check_count(&self) -> usize {
+        self.health_checks
+            .iter()
+            .filter(|running| !running.is_finished())
+            .count()
     }
 
     async fn create_health_client(
@@ -164,37 +329,96 @@ impl Hub {
         Ok(client)
     }
 
-    pub async fn broadcast(&self, instance: ServiceInstance) {
-        // broadcast to all subscribers
-        Self::broadcast_(instance, &self.broadcaster).await;
+    /// apply a change to the registry pool and publish its event atomically;
+    /// the change returns the event to publish, or None if nothing changed
+    pub fn commit<F>(&self, change: F) -> Option<u64>
+    where
+        F: FnOnce(&RegistryPool) -> Option<(EventKind, ServiceInstance)>,
+    {
+        self.subscriptions.commit(change)
     }
 
-    async fn broadcast_(instance: ServiceInstance, broadcaster: &PubSub) {
-        if let Some(chan) = broadcaster.get(&instance.name) {
-            let chan = chan.value();
-            let req = Service {
-                id: instance.id.clone(),
-                name: instance.name,
-                address: instance.address,
-                port: instance.port,
-                active: instance.status,
-                scheme: instance.scheme,
-            };
-            if let Err(e) = chan.send(Ok(req)) {
-                warn!("broadcast failed: {:?}", e);
-            }
-        }
+    /// the revision of the last published event
+    pub fn revision(&self) -> u64 {
+        self.subscriptions.revision()
+    }
+
+    /// the random identifier of this run of the server, see
+    /// [`SubscribeRequest::epoch`]
+    pub fn epoch(&self) -> u64 {
+        self.subscriptions.epoch()
+    }
+
+    /// number of open subscriptions
+    pub fn subscriber_count(&self) -> usize {
+        self.subscriptions.subscriber_count()
+    }
+
+    /// number of open subscriptions selecting the service
+    pub fn subscribers(&self, key: &ServiceKey) -> usize {
+        self.subscriptions.subscribers(key)
     }
 
-    pub fn query_by_name(&self, name: &str) -> Vec<Service> {
-        match self.registry_pool.get(name) {
+    /// number of events waiting in the queues of the subscribers
+    pub fn queued_events(&self) -> usize {
+        self.subscriptions.queued_events()
+    }
+
+    pub fn query_by_name(&self, key: &ServiceKey) -> Vec<Service> {
+        self.instances(key).into_iter().map(Service::from).collect()
+    }
+
+    /// all the registered instances of the service
+    pub fn instances(&self, key: &ServiceKey) -> Vec<ServiceInstance> {
+        match self.registry_pool.get(key) {
             None => Vec::new(),
-            Some(ins) => ins
-                .iter()
-                .map(|x| Service::from(x.value().clone()))
-                .collect(),
+            Some(ins) => ins.iter().map(|x| x.value().clone()).collect(),
         }
     }
+
+    /// all the registered instances of the selected services
+    pub fn select(&self, selector: &ServiceSelector) -> Vec<ServiceInstance> {
+        subscription::select(&self.registry_pool, selector)
+    }
+
+    /// the registered services and their number of instances, of every
+    /// namespace when none is given
+    pub fn catalog(&self, namespace: Option<&str>) -> Vec<(ServiceKey, usize)> {
+        let namespace = namespace.map(|namespace| ServiceKey::new(namespace, "").namespace);
+        let mut catalog: Vec<_> = self
+            .registry_pool
+            .iter()
+            .filter(|entry| {
+                namespace
+                    .as_ref()
+                    .is_none_or(|ns| &entry.key().namespace == ns)
+            })
+            .filter(|entry| !entry.value().is_empty())
+            .map(|entry| (entry.key().clone(), entry.value().len()))
+            .collect();
+        catalog.sort_by(|a, b| {
+            a.0.namespace
+                .cmp(&b.0.namespace)
+                .then(a.0.name.cmp(&b.0.name))
+        });
+        catalog
+    }
+
+    /// query the service with the given name in every namespace
+    pub fn query_all_namespaces(&self, name: &str) -> Vec<Service> {
+        let name = ServiceKey::new("", name).name;
+        self.registry_pool
+            .iter()
+            .filter(|entry| entry.key().name == name)
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .map(|x| Service::from(x.value().clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
 }
 
 impl Default for Hub {
@@ -210,40 +434,61 @@ impl ServiceRegistry for Hub {
         &self,
         request: Request<ServiceInstance>,
     ) -> Result<Response<OperationStatus>, Status> {
-        let instance = request.into_inner();
+        let identity = request.extensions().get::<PeerIdentity>().cloned();
+        let mut instance = request.into_inner();
+        validate_instance(&mut instance)?;
+        if instance.namespace.is_empty() {
+            instance.namespace = DEFAULT_NAMESPACE.to_string();
+        }
+        self.authorize(identity.as_ref(), Operation::Register(&instance))?;
         debug!("register service: {:?}", &instance);
-        if let Some(existing_services) = self.registry_pool.get(&instance.name) {
-            if let Some(existing_instance) = existing_services.get(&instance.id) {
-                // Skip if the instance already registered and the same as the new one
-                if instance == *existing_instance
-                    && existing_instance.health_check.is_some()
-                    && existing_instance.health_check.as_ref().unwrap().retries > 0
-                {
-                    return Ok(Response::new(OperationStatus {
-                        success: true,
-                        message: "service already registered".to_string(),
-                    }));
+
+        let mut already_registered = false;
+        self.commit(|pool| {
+            let existing = pool
+                .get(&instance.key())
+                .and_then(|instances| instances.get(&instance.id).map(|i| i.value().clone()));
+            let kind = match existing {
+                // Skip if the instance already registered and the same as the new one,
+                // so heartbeats re-registering an instance are not broadcast
+                Some(ref existing) if Self::is_renewal(&instance, existing) => {
+                    already_registered = true;
+                    return None;
                 }
+                Some(_) => EventKind::Updated,
+                None => EventKind::Added,
+            };
+            // register to registry pool
+            pool.entry(instance.key())
+                .or_default()
+                .insert(instance.id.clone(), instance.clone());
+            // stop the health check of the replaced registration before a
+            // probe of it can see the new one
+            if let Some((_, running)) = self
+                .health_checks
+                .remove(&(instance.key(), instance.id.clone()))
+            {
+                running.abort();
             }
+            Some((kind, instance.clone()))
+        });
+        if already_registered {
+            self.renew_health_check(&instance);
+            return Ok(Response::new(OperationStatus {
+                success: true,
+                message: "service already registered".to_string(),
+            }));
         }
-        // register to registry pool
-        self.registry_pool
-            .entry(instance.name.clone())
-            .or_default()
-            .insert(instance.id.clone(), instance.clone());
 
-        // check channel if exists
-        self.broadcaster
-            .entry(instance.name.clone())
-            .or_insert(broadcast::channel(100).0);
+        METRICS
+            .registrations
+            .with_label_values(&[&instance.namespace, &instance.name])
+            .inc();
 
         if instance.health_check.is_some() {
             self.health_check(instance.clone());
         }
 
-        // notify all subscribers
-        self.broadcast(instance).await;
-
         Ok(Response::new(OperationStatus {
             success: true,
             message: "register service success".to_string(),
@@ -254,19 +499,33 @@ impl ServiceRegistry for Hub {
         &self,
         request: Request<ServiceInstanceIdentifier>,
     ) -> Result<Response<OperationStatus>, Status> {
+        let identity = request.extensions().get::<PeerIdentity>().cloned();
         let identifier = request.into_inner();
+        self.authorize(identity.as_ref(), Operation::Unregister(&identifier))?;
         debug!("unregister service: {:?}", &identifier);
-        let instances = self.registry_pool.get(&identifier.name);
-        if instances.is_none() {
-            return Ok(Response::new(OperationStatus {
-                success: true,
-                message: "service not found".to_string(),
-            }));
+        let key = ServiceKey::new(identifier.namespace, identifier.name);
+        if !self.registry_pool.contains_key(&key) {
+            return Err(SynapseError::NotFound(format!("service {} not found", key.name)).into());
         }
-        let instances = instances.unwrap();
-        if let Some((_, instance)) = instances.remove(&identifier.id) {
+        let mut found = false;
+        self.commit(|pool| {
+            let (_, instance) = pool.get(&key)?.remove(&identifier.id)?;
+            found = true;
+            if let Some((_, running)) = self
+                .health_checks
+                .remove(&(key.clone(), identifier.id.clone()))
+            {
+                running.abort();
+            }
             // broadcast to all subscribers
-            self.broadcast(instance).await;
+            Some((EventKind::Removed, instance))
+        });
+        if !found {
+            return Err(SynapseError::NotFound(format!(
+                "instance {} of service {} not found",
+                identifier.id, key.name
+            ))
+            .into());
         }
 
         Ok(Response::new(OperationStatus {
@@ -279,75 +538,649 @@ impl ServiceRegistry for Hub {
         &self,
         request: Request<QueryRequest>,
     ) -> Result<Response<QueryResponse>, Status> {
-        let name = request.into_inner().name;
-        debug!("query services: {:?}", name);
-        let instances = self.query_by_name(&name);
+        let req = request.into_inner();
+        debug!("query services: {:?}", req);
+        let instances = if req.all_namespaces {
+            self.query_all_namespaces(&req.name)
+        } else {
+            self.query_by_name(&ServiceKey::new(req.namespace, req.name))
+        };
         Ok(Response::new(QueryResponse {
             services: instances,
         }))
     }
 
-    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<Service, Status>> + Send>>;
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<ServiceEvent, Status>> + Send>>;
 
-    // todo could we return multiple streams for multiple subscribe?
     async fn subscribe(
         &self,
         request: Request<SubscribeRequest>,
     ) -> Result<Response<Self::SubscribeStream>, Status> {
-        let name = request.into_inner().service;
-        debug!("subscribe: {:?}", name);
-        let rx = self
-            .broadcaster
-            .entry(name)
-            .or_insert_with(|| {
-                let (tx, _) = broadcast::channel(256);
-                tx
-            })
-            .subscribe();
-
-        let stream = BroadcastStream::new(rx).map(|message_result| {
-            message_result.unwrap_or_else(|recv_error| {
-                // 如果是Err，则将BroadcastStream的错误转换成gRPC的错误
-                Err(Status::internal(format!(
-                    "Broadcast error: {:?}",
-                    recv_error
-                )))
-            })
-        });
+        let req = request.into_inner();
+        debug!("subscribe: {:?}", req);
+        let selector = ServiceSelector::from_request(&req).ok_or_else(|| {
+            SynapseError::InvalidArgument("no service to subscribe to".to_string())
+        })?;
+        let start = Start::from_request(&req, Start::Live);
+        let subscription = self.subscriptions.subscribe(selector, req.filter, start);
+        let stream = subscription.into_stream();
         Ok(Response::new(Box::pin(stream)))
     }
 
-    type SubscribeToServiceStream = Pin<Box<dyn Stream<Item = Result<Service, Status>> + Send>>;
+    type SubscribeToServiceStream =
+        Pin<Box<dyn Stream<Item = Result<ServiceEvent, Status>> + Send>>;
 
     async fn subscribe_to_service(
         &self,
         request: Request<SubscribeRequest>,
     ) -> Result<Response<Self::SubscribeToServiceStream>, Status> {
-        let name = request.into_inner().service;
-        debug!("subscribe: {:?}", name);
-        let tx = self.broadcaster.entry(name.clone()).or_insert_with(|| {
-            let (tx, _) = broadcast::channel(256);
-            tx
+        let req = request.into_inner();
+        debug!("subscribe: {:?}", req);
+        let selector = ServiceSelector::from_request(&req).ok_or_else(|| {
+            SynapseError::InvalidArgument("no service to subscribe to".to_string())
+        })?;
+        // the snapshot is only sent to this subscriber, followed by live changes
+        let start = Start::from_request(&req, Start::Snapshot);
+        let subscription = self.subscriptions.subscribe(selector, req.filter, start);
+        let stream = subscription.into_stream();
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type SubscribeBatchedStream =
+        Pin<Box<dyn Stream<Item = Result<ServiceEventBatch, Status>> + Send>>;
+
+    async fn subscribe_batched(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeBatchedStream>, Status> {
+        let req = request.into_inner();
+        debug!("subscribe batched: {:?}", req);
+        let selector = ServiceSelector::from_request(&req).ok_or_else(|| {
+            SynapseError::InvalidArgument("no service to subscribe to".to_string())
+        })?;
+        let start = Start::from_request(&req, Start::Snapshot);
+        let window = Duration::from_millis(req.debounce_ms as u64);
+        let subscription = self.subscriptions.subscribe(selector, req.filter, start);
+        let stream = stream::unfold(subscription, move |mut subscription| async move {
+            let batch = subscription.next_batch(window).await?;
+            let batch = batch.map(|events| ServiceEventBatch { events });
+            Some((batch, subscription))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    fn instance(namespace: &str, name: &str, id: &str) -> ServiceInstance {
+        ServiceInstance {
+            id: id.to_string(),
+            name: name.to_string(),
+            address: "127.0.0.1".to_string(),
+            port: 8080,
+            namespace: namespace.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_are_isolated() {
+        let hub = Hub::new();
+        hub.register_service(Request::new(instance("team-a", "api", "1")))
+            .await
+            .unwrap();
+        hub.register_service(Request::new(instance("team-b", "api", "2")))
+            .await
+            .unwrap();
+        hub.register_service(Request::new(instance("", "api", "3")))
+            .await
+            .unwrap();
+
+        let services = hub.query_by_name(&ServiceKey::new("team-a", "api"));
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].id, "1");
+
+        // empty namespace falls back to the default one
+        let services = hub.query_by_name(&ServiceKey::new("", "api"));
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].namespace, DEFAULT_NAMESPACE);
+
+        let req = QueryRequest::new("api".to_string()).across_namespaces();
+        let res = hub.query_services(Request::new(req)).await.unwrap();
+        assert_eq!(res.into_inner().services.len(), 3);
+        assert_eq!(hub.query_all_namespaces(" API ").len(), 3);
+
+        let identifier = ServiceInstanceIdentifier::new("api".to_string(), "1".to_string())
+            .with_namespace("team-b");
+        let status = hub
+            .unregister_service(Request::new(identifier))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(
+            hub.query_by_name(&ServiceKey::new("team-a", "api")).len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_is_scoped_to_namespace() {
+        let hub = Hub::new();
+        let req = SubscribeRequest::new("api".to_string()).with_namespace("team-a");
+        let mut stream = hub.subscribe(Request::new(req)).await.unwrap().into_inner();
+
+        hub.register_service(Request::new(instance("team-b", "api", "2")))
+            .await
+            .unwrap();
+        hub.register_service(Request::new(instance("team-a", "api", "1")))
+            .await
+            .unwrap();
+
+        let event = stream.next().await.unwrap().unwrap();
+        let instance = event.instance.unwrap();
+        assert_eq!(instance.id, "1");
+        assert_eq!(instance.namespace, "team-a");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_event_kinds() {
+        let hub = Hub::new();
+        hub.register_service(Request::new(instance("", "api", "1")))
+            .await
+            .unwrap();
+        let req = SubscribeRequest::new("api".to_string());
+        let mut stream = hub
+            .subscribe_to_service(Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Added);
+        let snapshot_end = stream.next().await.unwrap().unwrap();
+        assert_eq!(snapshot_end.kind(), EventKind::SnapshotEnd);
+        assert_eq!(snapshot_end.revision, event.revision);
+
+        let mut changed = instance("", "api", "1");
+        changed.port = 9090;
+        hub.register_service(Request::new(changed)).await.unwrap();
+        let updated = stream.next().await.unwrap().unwrap();
+        assert_eq!(updated.kind(), EventKind::Updated);
+        assert_eq!(updated.instance.unwrap().port, 9090);
+
+        let identifier = ServiceInstanceIdentifier::new("api".to_string(), "1".to_string());
+        hub.unregister_service(Request::new(identifier))
+            .await
+            .unwrap();
+        let removed = stream.next().await.unwrap().unwrap();
+        assert_eq!(removed.kind(), EventKind::Removed);
+        assert!(removed.revision > updated.revision);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_is_sent_to_new_subscriber_only() {
+        let hub = Hub::new();
+        hub.register_service(Request::new(instance("", "api", "1")))
+            .await
+            .unwrap();
+        let req = SubscribeRequest::new("api".to_string());
+        let mut first = hub
+            .subscribe_to_service(Request::new(req.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            first.next().await.unwrap().unwrap().kind(),
+            EventKind::Added
+        );
+        let end = first.next().await.unwrap().unwrap();
+        assert_eq!(end.kind(), EventKind::SnapshotEnd);
+
+        let mut second = hub
+            .subscribe_to_service(Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        hub.register_service(Request::new(instance("", "api", "2")))
+            .await
+            .unwrap();
+
+        // the existing subscriber only sees the new instance
+        let event = first.next().await.unwrap().unwrap();
+        assert_eq!(event.instance.unwrap().id, "2");
+        assert_eq!(event.revision, end.revision + 1);
+
+        let ids: Vec<_> = second
+            .by_ref()
+            .take(3)
+            .map(|event| event.unwrap())
+            .map(|event| (event.kind(), event.instance.map(|i| i.id)))
+            .collect()
+            .await;
+        assert_eq!(
+            ids,
+            vec![
+                (EventKind::Added, Some("1".to_string())),
+                (EventKind::SnapshotEnd, None),
+                (EventKind::Added, Some("2".to_string())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lagged_subscriber_is_resynced() {
+        let hub = Hub::with_config(HubConfig {
+            channel_capacity: 2,
+            ..Default::default()
         });
+        let req = SubscribeRequest::new("api".to_string());
+        let mut stream = hub.subscribe(Request::new(req)).await.unwrap().into_inner();
+        for id in 1..=5 {
+            hub.register_service(Request::new(instance("", "api", &id.to_string())))
+                .await
+                .unwrap();
+        }
 
-        let rx = tx.subscribe();
-        let services = self.query_by_name(&name);
-        debug!("query_by_name: {:?}", services);
-        for service in services {
-            if let Err(e) = tx.send(Ok(service)) {
-                error!("Failed to send service to subscriber: {}", e);
+        let resync = stream.next().await.unwrap().unwrap();
+        assert_eq!(resync.kind(), EventKind::Resync);
+        assert_eq!(resync.revision, 5);
+        let mut ids = Vec::new();
+        loop {
+            let event = stream.next().await.unwrap().unwrap();
+            if event.kind() == EventKind::SnapshotEnd {
+                break;
             }
+            ids.push(event.instance.unwrap().id);
         }
+        ids.sort();
+        assert_eq!(ids, vec!["1", "2", "3", "4", "5"]);
 
-        let stream = BroadcastStream::new(rx).map(|message_result| {
-            message_result.unwrap_or_else(|recv_error| {
-                // 如果是Err，则将BroadcastStream的错误转换成gRPC的错误
-                Err(Status::internal(format!(
-                    "Broadcast error: {:?}",
-                    recv_error
-                )))
-            })
+        hub.register_service(Request::new(instance("", "api", "6")))
+            .await
+            .unwrap();
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.revision, 6);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_to_multiple_services() {
+        let hub = Hub::new();
+        for name in ["api", "web", "db"] {
+            hub.register_service(Request::new(instance("", name, "1")))
+                .await
+                .unwrap();
+        }
+        let req = SubscribeRequest::for_services(["api", "web"]);
+        let mut stream = hub
+            .subscribe_to_service(Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            names.push(stream.next().await.unwrap().unwrap().instance.unwrap().name);
+        }
+        names.sort();
+        assert_eq!(names, vec!["api", "web"]);
+        let end = stream.next().await.unwrap().unwrap();
+        assert_eq!(end.kind(), EventKind::SnapshotEnd);
+
+        let mut catalog = hub
+            .subscribe(Request::new(SubscribeRequest::catalog()))
+            .await
+            .unwrap()
+            .into_inner();
+        hub.register_service(Request::new(instance("", "db", "2")))
+            .await
+            .unwrap();
+        hub.register_service(Request::new(instance("", "web", "2")))
+            .await
+            .unwrap();
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.instance.unwrap().name, "web");
+        let event = catalog.next().await.unwrap().unwrap();
+        assert_eq!(event.instance.unwrap().name, "db");
+
+        let res = hub
+            .subscribe(Request::new(SubscribeRequest::default()))
+            .await;
+        assert_eq!(res.err().unwrap().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_revision() {
+        let hub = Hub::with_config(HubConfig {
+            journal_capacity: 2,
+            ..Default::default()
         });
-        Ok(Response::new(Box::pin(stream)))
+        hub.register_service(Request::new(instance("", "api", "1")))
+            .await
+            .unwrap();
+        hub.register_service(Request::new(instance("", "db", "1")))
+            .await
+            .unwrap();
+        hub.register_service(Request::new(instance("", "api", "2")))
+            .await
+            .unwrap();
+
+        let mut req = SubscribeRequest::new("api".to_string());
+        req.resume_from = 1;
+        req.epoch = hub.epoch();
+        let mut stream = hub
+            .subscribe_to_service(Request::new(req.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        // only the journaled change of the selected service is replayed
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!((event.kind(), event.revision), (EventKind::Added, 3));
+        let end = stream.next().await.unwrap().unwrap();
+        assert_eq!((end.kind(), end.revision), (EventKind::SnapshotEnd, 3));
+
+        // revision 1 has been evicted from the journal
+        hub.register_service(Request::new(instance("", "api", "3")))
+            .await
+            .unwrap();
+        let mut stream = hub
+            .subscribe(Request::new(req.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Resync);
+        let snapshot: Vec<_> = stream.by_ref().take(4).map(|e| e.unwrap()).collect().await;
+        assert_eq!(snapshot.last().unwrap().kind(), EventKind::SnapshotEnd);
+
+        // a revision of another run of the server is never replayed
+        req.resume_from = 4;
+        req.epoch = hub.epoch().wrapping_add(1);
+        let mut stream = hub.subscribe(Request::new(req)).await.unwrap().into_inner();
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Resync);
+        assert_eq!(event.epoch, hub.epoch());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_filter() {
+        let hub = Hub::new();
+        let mut v1 = instance("", "api", "1");
+        v1.version = "v1".to_string();
+        let mut v2 = instance("", "api", "2");
+        v2.version = "v2".to_string();
+        hub.register_service(Request::new(v1.clone()))
+            .await
+            .unwrap();
+        hub.register_service(Request::new(v2)).await.unwrap();
+
+        let filter = crate::pb::SubscriptionFilter {
+            version: "v1".to_string(),
+            healthy_only: true,
+            ..Default::default()
+        };
+        let req = SubscribeRequest::new("api".to_string()).with_filter(filter);
+        let mut stream = hub
+            .subscribe_to_service(Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.instance.unwrap().id, "1");
+        let end = stream.next().await.unwrap().unwrap();
+        assert_eq!(end.kind(), EventKind::SnapshotEnd);
+
+        // the instance goes down and no longer matches healthy_only
+        hub.commit(|pool| {
+            let instances = pool.get(&v1.key())?;
+            let mut instance = instances.get_mut(&v1.id)?;
+            instance.status = ServiceStatus::Down as i32;
+            Some((EventKind::StatusChanged, instance.clone()))
+        });
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Removed);
+        assert_eq!(event.instance.unwrap().id, "1");
+    }
+
+    #[tokio::test]
+    async fn test_queued_events_are_coalesced() {
+        let hub = Hub::new();
+        let req = SubscribeRequest::new("api".to_string());
+        let mut stream = hub.subscribe(Request::new(req)).await.unwrap().into_inner();
+        for port in [8081, 8082, 8083] {
+            let mut changed = instance("", "api", "1");
+            changed.port = port;
+            hub.register_service(Request::new(changed)).await.unwrap();
+        }
+        hub.register_service(Request::new(instance("", "api", "2")))
+            .await
+            .unwrap();
+
+        // the three registrations of instance 1 collapse into its final state
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Added);
+        assert_eq!(event.revision, 3);
+        assert_eq!(event.instance.unwrap().port, 8083);
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.instance.unwrap().id, "2");
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_keeps_health_checked_status() {
+        let hub = Hub::new();
+        let mut registered = instance("", "api", "1");
+        registered.health_check = Some(crate::pb::HealthCheck {
+            interval: 3600,
+            timeout: 1,
+            retries: 3,
+            ..Default::default()
+        });
+        hub.register_service(Request::new(registered.clone()))
+            .await
+            .unwrap();
+        assert_eq!(hub.health_check_count(), 1);
+
+        // the health check marks the instance down
+        let mut down = registered.clone();
+        hub.commit(|pool| {
+            Hub::modify_service_status(&mut down, ServiceStatus::Down, pool, 3);
+            Some((EventKind::StatusChanged, down.clone()))
+        });
+        let req = SubscribeRequest::new("api".to_string());
+        let mut stream = hub.subscribe(Request::new(req)).await.unwrap().into_inner();
+
+        for _ in 0..3 {
+            let res = hub
+                .register_service(Request::new(registered.clone()))
+                .await
+                .unwrap();
+            assert_eq!(res.into_inner().message, "service already registered");
+        }
+        let instances = hub.instances(&ServiceKey::new("", "api"));
+        assert_eq!(instances[0].status(), ServiceStatus::Down);
+        assert_eq!(hub.health_check_count(), 1);
+
+        // a changed registration replaces the health check
+        registered.port = 8081;
+        hub.register_service(Request::new(registered.clone()))
+            .await
+            .unwrap();
+        assert_eq!(hub.health_check_count(), 1);
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), EventKind::Updated);
+
+        let identifier = ServiceInstanceIdentifier::new("api".to_string(), "1".to_string());
+        hub.unregister_service(Request::new(identifier))
+            .await
+            .unwrap();
+        assert_eq!(hub.health_check_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_removed_by_registration() {
+        let hub = Hub::new();
+        let mut registered = instance("", "api", "1");
+        registered.health_check = Some(crate::pb::HealthCheck {
+            interval: 3600,
+            timeout: 1,
+            retries: 3,
+            ..Default::default()
+        });
+        hub.register_service(Request::new(registered.clone()))
+            .await
+            .unwrap();
+        assert_eq!(hub.health_check_count(), 1);
+
+        let mut probed = registered.clone();
+        registered.health_check = None;
+        hub.register_service(Request::new(registered.clone()))
+            .await
+            .unwrap();
+        assert_eq!(hub.health_check_count(), 0);
+        // a probe that was already running leaves the instance as it is
+        let revision = hub.commit(|pool| {
+            let (_, notify) = Hub::modify_service_status(&mut probed, ServiceStatus::Down, pool, 3);
+            notify.then(|| (EventKind::StatusChanged, probed.clone()))
+        });
+        assert_eq!(revision, None);
+        let instances = hub.instances(&ServiceKey::new("", "api"));
+        assert_eq!(instances[0].status(), ServiceStatus::Up);
+    }
+
+    #[tokio::test]
+    async fn test_commit_survives_panic() {
+        let hub = Hub::new();
+        let panicking = hub.clone();
+        let res = std::thread::spawn(move || {
+            panicking.commit(|_| panic!("change failed"));
+        })
+        .join();
+        assert!(res.is_err());
+
+        hub.register_service(Request::new(instance("", "api", "1")))
+            .await
+            .unwrap();
+        assert_eq!(hub.revision(), 1);
+        assert_eq!(hub.queued_events(), 0);
+    }
+
+    /// only the clients with a certificate of the service they register
+    #[derive(Debug)]
+    struct SameName;
+
+    impl Authorize for SameName {
+        fn authorize(
+            &self,
+            identity: Option<&PeerIdentity>,
+            operation: Operation<'_>,
+        ) -> Result<(), SynapseError> {
+            let name = match operation {
+                Operation::Register(instance) => &instance.name,
+                Operation::Unregister(identifier) => &identifier.name,
+            };
+            match identity.and_then(|identity| identity.common_name.as_ref()) {
+                Some(common_name) if common_name == name => Ok(()),
+                _ => Err(SynapseError::PermissionDenied(format!(
+                    "not allowed to change {}",
+                    name
+                ))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authorizer() {
+        let hub = Hub::with_config(HubConfig {
+            authorizer: Some(Arc::new(SameName)),
+            ..Default::default()
+        });
+        let status = hub
+            .register_service(Request::new(instance("", "api", "1")))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let identity = PeerIdentity {
+            subject: "CN=api".to_string(),
+            common_name: Some("api".to_string()),
+            dns_names: Vec::new(),
+            uris: Vec::new(),
+            ip_addresses: Vec::new(),
+        };
+        for name in ["api", "web"] {
+            let mut req = Request::new(instance("", name, "1"));
+            req.extensions_mut().insert(identity.clone());
+            let res = hub.register_service(req).await;
+            assert_eq!(res.is_ok(), name == "api");
+        }
+        let identifier = ServiceInstanceIdentifier::new("api".to_string(), "1".to_string());
+        let status = hub
+            .unregister_service(Request::new(identifier.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let mut req = Request::new(identifier);
+        req.extensions_mut().insert(identity);
+        hub.unregister_service(req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_is_disconnected() {
+        let hub = Hub::with_config(HubConfig {
+            channel_capacity: 1,
+            slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+            ..Default::default()
+        });
+        let req = SubscribeRequest::new("api".to_string());
+        let mut stream = hub.subscribe(Request::new(req)).await.unwrap().into_inner();
+        assert_eq!(hub.subscriber_count(), 1);
+        for id in ["1", "2"] {
+            hub.register_service(Request::new(instance("", "api", id)))
+                .await
+                .unwrap();
+        }
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(stream.next().await.is_none());
+
+        // the queue of a closed subscription is cleaned up
+        drop(stream);
+        assert_eq!(hub.subscriber_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_batched() {
+        let hub = Hub::new();
+        hub.register_service(Request::new(instance("", "api", "1")))
+            .await
+            .unwrap();
+        let mut req = SubscribeRequest::new("api".to_string());
+        req.debounce_ms = 50;
+        let mut stream = hub
+            .subscribe_batched(Request::new(req))
+            .await
+            .unwrap()
+            .into_inner();
+        let snapshot = stream.next().await.unwrap().unwrap();
+        assert_eq!(snapshot.events.len(), 2);
+        assert_eq!(snapshot.events[1].kind(), EventKind::SnapshotEnd);
+
+        // a rolling restart within the window is sent as one batch
+        for port in [8081, 8082] {
+            for id in ["1", "2"] {
+                let mut changed = instance("", "api", id);
+                changed.port = port;
+                hub.register_service(Request::new(changed)).await.unwrap();
+            }
+        }
+        let batch = stream.next().await.unwrap().unwrap();
+        let ports: Vec<_> = batch
+            .events
+            .iter()
+            .map(|e| e.instance.as_ref().unwrap().port)
+            .collect();
+        assert_eq!(ports, vec![8082, 8082]);
     }
 }
@@ -1,9 +1,11 @@
 pub mod client;
 pub mod hub;
+pub mod subscription;
+pub mod validation;
 
 pub use crate::pb::{
     service_registry_client::ServiceRegistryClient, service_registry_server::ServiceRegistry,
-    service_registry_server::ServiceRegistryServer, OperationStatus, QueryRequest, QueryResponse,
-    Scheme, Service, ServiceInstance, ServiceInstanceIdentifier, ServiceStatus, ServingStatus,
-    SubscribeRequest,
+    service_registry_server::ServiceRegistryServer, EventKind, OperationStatus, QueryRequest,
+    QueryResponse, Scheme, Service, ServiceEvent, ServiceEventBatch, ServiceInstance,
+    ServiceInstanceIdentifier, ServiceStatus, ServingStatus, SubscribeRequest, SubscriptionFilter,
 };
@@ -0,0 +1,759 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use futures::{stream, Stream};
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+use tonic::Status;
+use tracing::{debug, warn};
+
+use crate::metrics::METRICS;
+use crate::pb::{
+    EventKind, ServiceEvent, ServiceInstance, ServiceStatus, SubscribeRequest, SubscriptionFilter,
+};
+use crate::service::hub::{Namespace, RegistryPool, ServiceId, ServiceKey, ServiceName};
+use crate::service::validation::normalize_name;
+
+/// the services a subscription receives events for, always scoped to a
+/// single namespace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceSelector {
+    namespace: Namespace,
+    names: HashSet<ServiceName>,
+    prefix: Option<String>,
+    all: bool,
+}
+
+impl ServiceSelector {
+    /// build the selector of the request, None if it selects nothing
+    pub fn from_request(req: &SubscribeRequest) -> Option<Self> {
+        let names: HashSet<ServiceName> = std::iter::once(&req.service)
+            .chain(req.services.iter())
+            .map(|name| normalize_name(name))
+            .filter(|name| !name.is_empty())
+            .collect();
+        let prefix = normalize_name(&req.prefix);
+        let prefix = (!prefix.is_empty()).then_some(prefix);
+        if names.is_empty() && prefix.is_none() && !req.all {
+            return None;
+        }
+        Some(Self {
+            namespace: ServiceKey::new(req.namespace.clone(), "").namespace,
+            names,
+            prefix,
+            all: req.all,
+        })
+    }
+
+    /// whether the event belongs to a selected service
+    pub fn selects(&self, event: &ServiceEvent) -> bool {
+        event
+            .instance
+            .as_ref()
+            .is_some_and(|instance| self.matches(&instance.key()))
+    }
+
+    pub fn matches(&self, key: &ServiceKey) -> bool {
+        key.namespace == self.namespace
+            && (self.all
+                || self.names.contains(&key.name)
+                || self
+                    .prefix
+                    .as_ref()
+                    .is_some_and(|prefix| key.name.starts_with(prefix)))
+    }
+}
+
+impl SubscriptionFilter {
+    pub fn matches(&self, instance: &ServiceInstance) -> bool {
+        self.tags.iter().all(|tag| instance.tags.contains(tag))
+            && self
+                .metadata
+                .iter()
+                .all(|(key, value)| instance.metadata.get(key) == Some(value))
+            && (self.version.is_empty() || self.version == instance.version)
+            && (!self.healthy_only || instance.status == ServiceStatus::Up as i32)
+    }
+}
+
+/// applies the subscription filter to the events of a subscriber, tracking
+/// which instances the subscriber can see so that an instance entering the
+/// filter is ADDED and an instance leaving it is REMOVED
+#[derive(Debug)]
+pub struct FilteredView {
+    filter: Option<SubscriptionFilter>,
+    visible: HashMap<(ServiceKey, ServiceId), bool>,
+    /// whether an instance the view knows nothing about may be known to the
+    /// subscriber, true when it did not start from a snapshot
+    assume_visible: bool,
+}
+
+impl FilteredView {
+    pub fn new(filter: Option<SubscriptionFilter>, assume_visible: bool) -> Self {
+        Self {
+            filter,
+            visible: HashMap::new(),
+            assume_visible,
+        }
+    }
+
+    /// the event as the subscriber should see it, None if it is filtered out
+    pub fn apply(&mut self, mut event: ServiceEvent) -> Option<ServiceEvent> {
+        let Some(filter) = &self.filter else {
+            return Some(event);
+        };
+        let Some(instance) = &event.instance else {
+            if event.kind() == EventKind::Resync {
+                self.visible.clear();
+                self.assume_visible = false;
+            }
+            return Some(event);
+        };
+        let key = (instance.key(), instance.id.clone());
+        let matches = filter.matches(instance);
+        let was_visible = self
+            .visible
+            .get(&key)
+            .copied()
+            .unwrap_or(self.assume_visible);
+
+        if event.kind() == EventKind::Removed || !matches {
+            self.visible.remove(&key);
+            if !was_visible {
+                return None;
+            }
+            // the instance left the filter, the subscriber sees it removed
+            event.kind = EventKind::Removed as i32;
+            return Some(event);
+        }
+        if !was_visible {
+            event.kind = EventKind::Added as i32;
+        }
+        self.visible.insert(key, true);
+        Some(event)
+    }
+}
+
+/// the most recent events, kept to replay them to resuming subscribers
+#[derive(Debug)]
+pub(crate) struct Journal {
+    /// random identifier of this run of the server, revisions restart from 0
+    /// on every run
+    epoch: u64,
+    revision: u64,
+    events: VecDeque<ServiceEvent>,
+    capacity: usize,
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            // never 0, which stands for no epoch in requests
+            epoch: rand::random::<u64>().max(1),
+            revision: 0,
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// the revision of the last event
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// stamp the change with the next revision and record it
+    pub fn append(&mut self, kind: EventKind, instance: ServiceInstance) -> ServiceEvent {
+        self.revision += 1;
+        let event = ServiceEvent::new(kind, instance, self.revision);
+        if self.capacity > 0 {
+            if self.events.len() == self.capacity {
+                self.events.pop_front();
+            }
+            self.events.push_back(event.clone());
+        }
+        event
+    }
+
+    /// the events after the revision, None if some of them were evicted or
+    /// the revision was issued by another run of the server
+    pub fn since(&self, epoch: u64, revision: u64) -> Option<impl Iterator<Item = &ServiceEvent>> {
+        if epoch != self.epoch || revision > self.revision {
+            return None;
+        }
+        let oldest = self
+            .events
+            .front()
+            .map_or(self.revision + 1, |event| event.revision);
+        if revision + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.events
+                .iter()
+                .filter(move |event| event.revision > revision),
+        )
+    }
+}
+
+/// what to do with a subscriber whose queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SlowConsumerPolicy {
+    /// drop the new event, the subscriber misses it
+    Drop,
+    /// drop the queued events and send the subscriber a fresh snapshot
+    #[default]
+    Resync,
+    /// close the subscription with RESOURCE_EXHAUSTED
+    Disconnect,
+}
+
+/// where a new subscription starts
+#[derive(Clone, Copy, Debug)]
+pub enum Start {
+    /// live changes only
+    Live,
+    /// a snapshot of the selected services, then live changes
+    Snapshot,
+    /// a RESYNC marker and a snapshot, for a subscriber that lost events
+    Resync,
+    /// the journaled events after the revision of the epoch, or a resync if
+    /// they are gone
+    Resume { epoch: u64, revision: u64 },
+}
+
+impl Start {
+    /// resume from the revision of the request if it has one
+    pub fn from_request(req: &SubscribeRequest, fresh: Start) -> Self {
+        match req.resume_from {
+            0 => fresh,
+            revision => Start::Resume {
+                epoch: req.epoch,
+                revision,
+            },
+        }
+    }
+}
+
+/// all the registered instances of the selected services
+pub fn select(pool: &RegistryPool, selector: &ServiceSelector) -> Vec<ServiceInstance> {
+    pool.iter()
+        .filter(|entry| selector.matches(entry.key()))
+        .flat_map(|entry| {
+            entry
+                .value()
+                .iter()
+                .map(|x| x.value().clone())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// fans the committed changes out to a bounded queue per subscriber, so a
+/// slow subscriber only affects itself
+#[derive(Clone, Debug)]
+pub struct SubscriptionManager {
+    pool: RegistryPool,
+    /// the lock serializes registry changes with their events
+    inner: Arc<Mutex<Inner>>,
+    queue_capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+#[derive(Debug)]
+struct Inner {
+    journal: Journal,
+    next_id: u64,
+    subscribers: HashMap<u64, Arc<Queue>>,
+}
+
+/// lock the mutex even if a thread panicked while holding it, the state it
+/// guards stays consistent and a panic must not stop every later change
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl SubscriptionManager {
+    pub fn new(
+        pool: RegistryPool,
+        journal_capacity: usize,
+        queue_capacity: usize,
+        policy: SlowConsumerPolicy,
+    ) -> Self {
+        Self {
+            pool,
+            inner: Arc::new(Mutex::new(Inner {
+                journal: Journal::new(journal_capacity),
+                next_id: 0,
+                subscribers: HashMap::new(),
+            })),
+            queue_capacity,
+            policy,
+        }
+    }
+
+    /// apply a change to the registry pool and publish its event atomically;
+    /// the change returns the event to publish, or None if nothing changed
+    pub fn commit<F>(&self, change: F) -> Option<u64>
+    where
+        F: FnOnce(&RegistryPool) -> Option<(EventKind, ServiceInstance)>,
+    {
+        let mut inner = lock(&self.inner);
+        let (kind, instance) = change(&self.pool)?;
+        let event = inner.journal.append(kind, instance);
+        let revision = event.revision;
+        for queue in inner.subscribers.values() {
+            if queue.selector.selects(&event) {
+                queue.push(event.clone(), self.queue_capacity, self.policy);
+            }
+        }
+        Some(revision)
+    }
+
+    /// the revision of the last published event
+    pub fn revision(&self) -> u64 {
+        lock(&self.inner).journal.revision()
+    }
+
+    /// the random identifier of this run of the server
+    pub fn epoch(&self) -> u64 {
+        lock(&self.inner).journal.epoch()
+    }
+
+    /// number of open subscriptions
+    pub fn subscriber_count(&self) -> usize {
+        lock(&self.inner).subscribers.len()
+    }
+
+    /// number of open subscriptions selecting the service
+    pub fn subscribers(&self, key: &ServiceKey) -> usize {
+        let inner = lock(&self.inner);
+        inner
+            .subscribers
+            .values()
+            .filter(|queue| queue.selector.matches(key))
+            .count()
+    }
+
+    /// number of events waiting in the queues of the subscribers
+    pub fn queued_events(&self) -> usize {
+        let inner = lock(&self.inner);
+        inner
+            .subscribers
+            .values()
+            .map(|queue| lock(&queue.state).events.len())
+            .sum()
+    }
+
+    /// register a subscriber and collect the events preceding its live
+    /// changes under the commit lock, so every change is either in the
+    /// pending events or in the queue, never in both
+    pub fn subscribe(
+        &self,
+        selector: ServiceSelector,
+        filter: Option<SubscriptionFilter>,
+        start: Start,
+    ) -> Subscription {
+        let mut inner = lock(&self.inner);
+        let pending = self.pending(&inner.journal, &selector, start);
+        debug!("pending events: {:?}", pending);
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let queue = Arc::new(Queue {
+            selector,
+            state: Mutex::default(),
+            notify: Notify::new(),
+        });
+        inner.subscribers.insert(id, queue.clone());
+        Subscription {
+            manager: self.clone(),
+            id,
+            epoch: inner.journal.epoch(),
+            queue,
+            // a subscriber that did not start from a snapshot may know
+            // instances the view has not seen yet
+            view: FilteredView::new(filter, !matches!(start, Start::Snapshot)),
+            pending,
+            done: false,
+        }
+    }
+
+    /// drop the queued events of a slow subscriber and replace them with a
+    /// fresh snapshot
+    fn resync(&self, queue: &Queue) -> VecDeque<ServiceEvent> {
+        let inner = lock(&self.inner);
+        let mut state = lock(&queue.state);
+        state.events.clear();
+        state.resync = false;
+        self.pending(&inner.journal, &queue.selector, Start::Resync)
+    }
+
+    fn pending(
+        &self,
+        journal: &Journal,
+        selector: &ServiceSelector,
+        start: Start,
+    ) -> VecDeque<ServiceEvent> {
+        let revision = journal.revision();
+        let mut pending = VecDeque::new();
+        let resync = match start {
+            Start::Live => return pending,
+            Start::Snapshot => false,
+            Start::Resync => true,
+            Start::Resume {
+                epoch,
+                revision: from,
+            } => match journal.since(epoch, from) {
+                Some(events) => {
+                    pending.extend(events.filter(|e| selector.selects(e)).cloned());
+                    pending.push_back(ServiceEvent::snapshot_end(revision));
+                    return pending;
+                }
+                None => {
+                    debug!(
+                        "revision {} of epoch {} is not journaled, resync",
+                        from, epoch
+                    );
+                    true
+                }
+            },
+        };
+        if resync {
+            pending.push_back(ServiceEvent::resync(revision));
+        }
+        pending.extend(
+            select(&self.pool, selector)
+                .into_iter()
+                .map(|instance| ServiceEvent::new(EventKind::Added, instance, revision)),
+        );
+        pending.push_back(ServiceEvent::snapshot_end(revision));
+        pending
+    }
+}
+
+/// the bounded queue of the live changes of a subscriber
+#[derive(Debug)]
+struct Queue {
+    selector: ServiceSelector,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    events: VecDeque<ServiceEvent>,
+    /// the queue overflowed, the subscriber needs a fresh snapshot
+    resync: bool,
+    /// the queue overflowed, the subscription is closed
+    disconnected: bool,
+}
+
+enum Next {
+    Event(Box<ServiceEvent>),
+    Resync,
+    Disconnected,
+    Empty,
+}
+
+impl Queue {
+    fn push(&self, event: ServiceEvent, capacity: usize, policy: SlowConsumerPolicy) {
+        let mut state = lock(&self.state);
+        if state.resync || state.disconnected {
+            // the change will be part of the snapshot, or never be sent
+            return;
+        }
+        // a queued event of the same instance is superseded by the new one
+        let queued = state
+            .events
+            .iter()
+            .position(|queued| same_instance(queued, &event));
+        if let Some(position) = queued {
+            let queued = state.events.remove(position).unwrap();
+            // keep the queue ordered by revision
+            state.events.extend(coalesce(queued, event));
+        } else if state.events.len() < capacity {
+            state.events.push_back(event);
+        } else {
+            warn!(
+                "queue of the subscriber of {:?} is full, {:?}",
+                self.selector, policy
+            );
+            METRICS.queue_overflow(policy, state.events.len() + 1);
+            match policy {
+                SlowConsumerPolicy::Drop => return,
+                SlowConsumerPolicy::Resync => state.resync = true,
+                SlowConsumerPolicy::Disconnect => state.disconnected = true,
+            }
+            state.events.clear();
+        }
+        self.notify.notify_one();
+    }
+
+    fn pop(&self) -> Next {
+        let mut state = lock(&self.state);
+        if state.disconnected {
+            Next::Disconnected
+        } else if state.resync {
+            Next::Resync
+        } else {
+            state
+                .events
+                .pop_front()
+                .map_or(Next::Empty, |event| Next::Event(Box::new(event)))
+        }
+    }
+}
+
+fn same_instance(a: &ServiceEvent, b: &ServiceEvent) -> bool {
+    match (&a.instance, &b.instance) {
+        (Some(a), Some(b)) => a.id == b.id && a.name == b.name && a.namespace == b.namespace,
+        _ => false,
+    }
+}
+
+/// merge a queued event with a newer event of the same instance, None if the
+/// subscriber does not need to see either of them
+fn coalesce(queued: ServiceEvent, mut event: ServiceEvent) -> Option<ServiceEvent> {
+    match (queued.kind(), event.kind()) {
+        // the subscriber never saw the instance
+        (EventKind::Added, EventKind::Removed) => return None,
+        (EventKind::Added, _) => event.kind = EventKind::Added as i32,
+        // the subscriber saw the instance before it was removed
+        (EventKind::Removed, _) => event.kind = EventKind::Updated as i32,
+        _ => {}
+    }
+    Some(event)
+}
+
+fn add_to_batch(batch: &mut Vec<ServiceEvent>, event: ServiceEvent) {
+    if event.kind() == EventKind::Resync {
+        // the batched changes are superseded by the snapshot that follows
+        batch.clear();
+    } else if let Some(position) = batch.iter().position(|e| same_instance(e, &event)) {
+        let batched = batch.remove(position);
+        batch.extend(coalesce(batched, event));
+        return;
+    }
+    batch.push(event);
+}
+
+/// a subscriber of the [`SubscriptionManager`], unregistered when dropped
+pub struct Subscription {
+    manager: SubscriptionManager,
+    id: u64,
+    epoch: u64,
+    queue: Arc<Queue>,
+    view: FilteredView,
+    pending: VecDeque<ServiceEvent>,
+    done: bool,
+}
+
+impl Subscription {
+    /// the next event of the selected services that passes the filter, None
+    /// once the subscription is closed
+    pub async fn next(&mut self) -> Option<Result<ServiceEvent, Status>> {
+        if self.done {
+            return None;
+        }
+        loop {
+            let event = match self.pending.pop_front() {
+                Some(event) => event,
+                None => match self.queue.pop() {
+                    Next::Event(event) => *event,
+                    Next::Resync => {
+                        self.pending = self.manager.resync(&self.queue);
+                        continue;
+                    }
+                    Next::Disconnected => {
+                        self.done = true;
+                        return Some(Err(Status::resource_exhausted(
+                            "subscriber is too slow, disconnected",
+                        )));
+                    }
+                    Next::Empty => {
+                        self.queue.notify.notified().await;
+                        continue;
+                    }
+                },
+            };
+            if let Some(mut event) = self.view.apply(event) {
+                event.epoch = self.epoch;
+                return Some(Ok(event));
+            }
+        }
+    }
+
+    /// the changes received during the debounce window following the next
+    /// event, keeping only the final state of each instance
+    pub async fn next_batch(
+        &mut self,
+        window: Duration,
+    ) -> Option<Result<Vec<ServiceEvent>, Status>> {
+        let mut batch = match self.next().await? {
+            Ok(event) => vec![event],
+            Err(status) => return Some(Err(status)),
+        };
+        let deadline = Instant::now() + window;
+        // next is cancel safe, an event is never taken out of the queue
+        // without being returned
+        while let Ok(Some(event)) = time::timeout_at(deadline, self.next()).await {
+            match event {
+                Ok(event) => add_to_batch(&mut batch, event),
+                Err(status) => return Some(Err(status)),
+            }
+        }
+        Some(Ok(batch))
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<ServiceEvent, Status>> {
+        stream::unfold(self, |mut subscription| async move {
+            let event = subscription.next().await?;
+            Some((event, subscription))
+        })
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        lock(&self.manager.inner).subscribers.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selector_matches() {
+        let mut req = SubscribeRequest::for_services(["api", "web"]);
+        let selector = ServiceSelector::from_request(&req).unwrap();
+        assert!(selector.matches(&ServiceKey::new("", "api")));
+        assert!(selector.matches(&ServiceKey::new("default", "web")));
+        assert!(!selector.matches(&ServiceKey::new("team-a", "api")));
+        assert!(!selector.matches(&ServiceKey::new("", "db")));
+
+        req = SubscribeRequest::with_prefix("pay-");
+        let selector = ServiceSelector::from_request(&req).unwrap();
+        assert!(selector.matches(&ServiceKey::new("", "pay-gateway")));
+        assert!(!selector.matches(&ServiceKey::new("", "api")));
+
+        req = SubscribeRequest::catalog().with_namespace("team-a");
+        let selector = ServiceSelector::from_request(&req).unwrap();
+        assert!(selector.matches(&ServiceKey::new("team-a", "anything")));
+        assert!(!selector.matches(&ServiceKey::new("", "anything")));
+
+        assert!(ServiceSelector::from_request(&SubscribeRequest::default()).is_none());
+    }
+
+    #[test]
+    fn test_journal_since() {
+        let mut journal = Journal::new(3);
+        let epoch = journal.epoch();
+        assert_eq!(journal.since(epoch, 0).unwrap().count(), 0);
+        for _ in 0..5 {
+            journal.append(EventKind::Added, ServiceInstance::default());
+        }
+        assert_eq!(journal.revision(), 5);
+
+        let revisions: Vec<_> = journal
+            .since(epoch, 2)
+            .unwrap()
+            .map(|e| e.revision)
+            .collect();
+        assert_eq!(revisions, vec![3, 4, 5]);
+        assert_eq!(journal.since(epoch, 5).unwrap().count(), 0);
+        // revision 2 was evicted
+        assert!(journal.since(epoch, 1).is_none());
+        // issued by a previous run of the server, ahead or behind this one
+        assert!(journal.since(epoch, 9).is_none());
+        assert!(journal.since(epoch.wrapping_add(1), 3).is_none());
+        assert!(journal.since(0, 3).is_none());
+    }
+
+    #[test]
+    fn test_filtered_view() {
+        let filter = SubscriptionFilter {
+            tags: vec!["zone-a".to_string()],
+            healthy_only: true,
+            ..Default::default()
+        };
+        let mut view = FilteredView::new(Some(filter), false);
+        let mut instance = ServiceInstance {
+            id: "1".to_string(),
+            name: "api".to_string(),
+            tags: vec!["zone-a".to_string()],
+            ..Default::default()
+        };
+        let other = ServiceInstance {
+            id: "2".to_string(),
+            name: "api".to_string(),
+            ..Default::default()
+        };
+        assert!(view
+            .apply(ServiceEvent::new(EventKind::Added, other.clone(), 1))
+            .is_none());
+
+        let event = view.apply(ServiceEvent::new(EventKind::Added, instance.clone(), 2));
+        assert_eq!(event.unwrap().kind(), EventKind::Added);
+
+        // the instance goes down and leaves the healthy_only filter
+        instance.status = ServiceStatus::Down as i32;
+        let event = view.apply(ServiceEvent::new(
+            EventKind::StatusChanged,
+            instance.clone(),
+            3,
+        ));
+        assert_eq!(event.unwrap().kind(), EventKind::Removed);
+        assert!(view
+            .apply(ServiceEvent::new(EventKind::Removed, instance.clone(), 4))
+            .is_none());
+
+        // and comes back
+        instance.status = ServiceStatus::Up as i32;
+        let event = view.apply(ServiceEvent::new(EventKind::StatusChanged, instance, 5));
+        assert_eq!(event.unwrap().kind(), EventKind::Added);
+    }
+
+    #[test]
+    fn test_add_to_batch() {
+        let instance = |id: &str, port| ServiceInstance {
+            id: id.to_string(),
+            name: "api".to_string(),
+            port,
+            ..Default::default()
+        };
+        let mut batch = Vec::new();
+        add_to_batch(
+            &mut batch,
+            ServiceEvent::new(EventKind::Added, instance("1", 1), 1),
+        );
+        add_to_batch(
+            &mut batch,
+            ServiceEvent::new(EventKind::Added, instance("2", 1), 2),
+        );
+        add_to_batch(
+            &mut batch,
+            ServiceEvent::new(EventKind::Updated, instance("1", 2), 3),
+        );
+        let final_state: Vec<_> = batch
+            .iter()
+            .map(|e| (e.kind(), e.revision, e.instance.as_ref().unwrap().port))
+            .collect();
+        assert_eq!(
+            final_state,
+            vec![(EventKind::Added, 2, 1), (EventKind::Added, 3, 2)]
+        );
+
+        add_to_batch(
+            &mut batch,
+            ServiceEvent::new(EventKind::Removed, instance("2", 1), 4),
+        );
+        assert_eq!(batch.len(), 1);
+        add_to_batch(&mut batch, ServiceEvent::resync(5));
+        assert_eq!(batch, vec![ServiceEvent::resync(5)]);
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, FieldViolation, StatusExt};
+
+use crate::service::{Scheme, ServiceInstance, ServiceStatus};
+
+const MAX_NAME_LENGTH: usize = 128;
+
+/// the fields of a request which failed validation, returned as
+/// `INVALID_ARGUMENT` with a `BadRequest` detail per field
+#[derive(Debug, Default, Clone)]
+pub struct ValidationError {
+    pub violations: Vec<FieldViolation>,
+}
+
+impl ValidationError {
+    fn check(&mut self, valid: bool, field: &str, description: impl Into<String>) {
+        if !valid {
+            self.violations
+                .push(FieldViolation::new(field, description.into()));
+        }
+    }
+
+    fn into_result(self) -> Result<(), Self> {
+        if self.violations.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<_> = self
+            .violations
+            .iter()
+            .map(|violation| format!("{}: {}", violation.field, violation.description))
+            .collect();
+        write!(f, "invalid {}", fields.join(", "))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<ValidationError> for Status {
+    fn from(error: ValidationError) -> Self {
+        let message = error.to_string();
+        let details = ErrorDetails::with_bad_request(error.violations);
+        Status::with_error_details(Code::InvalidArgument, message, details)
+    }
+}
+
+/// service names and namespaces are case insensitive and trimmed
+pub fn normalize_name(name: &str) -> String {
+    name.trim().to_ascii_lowercase()
+}
+
+/// a normalized name made of lowercase letters, digits, `-`, `_` and `.`,
+/// starting with a letter or a digit
+fn is_valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c))
+}
+
+fn is_valid_host(address: &str) -> bool {
+    address.parse::<IpAddr>().is_ok()
+        || (!address.is_empty()
+            && address.len() <= 253
+            && address.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            }))
+}
+
+/// normalize the names of a registered instance and check its fields
+pub fn validate_instance(instance: &mut ServiceInstance) -> Result<(), ValidationError> {
+    instance.name = normalize_name(&instance.name);
+    instance.namespace = normalize_name(&instance.namespace);
+    instance.id = instance.id.trim().to_string();
+    instance.address = instance.address.trim().to_string();
+
+    let mut error = ValidationError::default();
+    error.check(!instance.name.is_empty(), "name", "must not be empty");
+    error.check(
+        instance.name.is_empty() || is_valid_name(&instance.name),
+        "name",
+        "must be made of letters, digits, '-', '_' and '.' and start with a letter or a digit",
+    );
+    error.check(
+        instance.namespace.is_empty() || is_valid_name(&instance.namespace),
+        "namespace",
+        "must be made of letters, digits, '-', '_' and '.' and start with a letter or a digit",
+    );
+    error.check(!instance.id.is_empty(), "id", "must not be empty");
+    error.check(
+        is_valid_host(&instance.address),
+        "address",
+        "must be an ip address or a host name",
+    );
+    error.check(
+        (1..=65535).contains(&instance.port),
+        "port",
+        "must be between 1 and 65535",
+    );
+    error.check(
+        Scheme::try_from(instance.scheme).is_ok(),
+        "scheme",
+        "unknown scheme",
+    );
+    error.check(
+        ServiceStatus::try_from(instance.status).is_ok(),
+        "status",
+        "unknown status",
+    );
+    if let Some(ref health) = instance.health_check {
+        error.check(
+            health.interval > 0,
+            "health_check.interval",
+            "must be positive",
+        );
+        error.check(
+            health.timeout > 0,
+            "health_check.timeout",
+            "must be positive",
+        );
+        error.check(
+            health.retries >= 0,
+            "health_check.retries",
+            "must not be negative",
+        );
+        error.check(
+            Scheme::try_from(health.scheme).is_ok(),
+            "health_check.scheme",
+            "unknown scheme",
+        );
+    }
+    error.into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pb::HealthCheck;
+
+    use super::*;
+
+    fn instance() -> ServiceInstance {
+        ServiceInstance {
+            id: "1".to_string(),
+            name: " Api ".to_string(),
+            address: "127.0.0.1".to_string(),
+            port: 8080,
+            ..Default::default()
+        }
+    }
+
+    fn fields(instance: ServiceInstance) -> Vec<String> {
+        let mut instance = instance;
+        match validate_instance(&mut instance) {
+            Ok(()) => Vec::new(),
+            Err(error) => error.violations.into_iter().map(|v| v.field).collect(),
+        }
+    }
+
+    #[test]
+    fn test_validate_instance() {
+        let mut valid = instance();
+        validate_instance(&mut valid).unwrap();
+        assert_eq!(valid.name, "api");
+
+        let mut invalid = ServiceInstance {
+            id: " ".to_string(),
+            name: "".to_string(),
+            address: "bad address".to_string(),
+            port: 70000,
+            scheme: 7,
+            health_check: Some(HealthCheck {
+                interval: 0,
+                timeout: 1,
+                ..Default::default()
+            }),
+            ..instance()
+        };
+        assert_eq!(
+            fields(invalid.clone()),
+            vec![
+                "name",
+                "id",
+                "address",
+                "port",
+                "scheme",
+                "health_check.interval"
+            ]
+        );
+
+        invalid.name = "api/v1".to_string();
+        assert_eq!(fields(invalid)[0], "name");
+        assert!(fields(ServiceInstance {
+            address: "api.internal".to_string(),
+            ..instance()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn test_validation_status() {
+        let mut invalid = ServiceInstance {
+            port: -1,
+            ..instance()
+        };
+        let status = Status::from(validate_instance(&mut invalid).unwrap_err());
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let bad_request = status.get_details_bad_request().unwrap();
+        assert_eq!(bad_request.field_violations[0].field, "port");
+    }
+}
@@ -0,0 +1,310 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+use tonic::{Request, Status};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+use crate::error::SynapseError;
+use crate::service::{ServiceInstance, ServiceInstanceIdentifier};
+
+/// the tls configuration of the server; clients must present a certificate
+/// signed by the client ca when one is given
+pub fn server_tls_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<ServerTlsConfig> {
+    let identity = Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?);
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(client_ca) = client_ca {
+        config = config
+            .client_ca_root(Certificate::from_pem(std::fs::read(client_ca)?))
+            .client_auth_optional(false);
+    }
+    Ok(config)
+}
+
+/// the tls configuration of the http api, the same as the one of the grpc
+/// server given the same files
+pub fn http_tls_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let certs = read_certs(cert)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key"))?;
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots.add(cert).map_err(io::Error::other)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// the tls configuration of a client of synapse, trusting the server
+/// certificates signed by the ca. the client presents the certificate and key
+/// of `identity` to a server enforcing mutual tls, and checks the server
+/// certificate against `domain` instead of the host it connects to
+pub fn client_tls_config(
+    ca: &Path,
+    identity: Option<(&Path, &Path)>,
+    domain: Option<&str>,
+) -> io::Result<ClientTlsConfig> {
+    let mut config =
+        ClientTlsConfig::new().ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+    if let Some((cert, key)) = identity {
+        config = config.identity(Identity::from_pem(
+            std::fs::read(cert)?,
+            std::fs::read(key)?,
+        ));
+    }
+    if let Some(domain) = domain {
+        config = config.domain_name(domain);
+    }
+    Ok(config)
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)).collect()
+}
+
+/// the identity of a client authenticated with a certificate, for the
+/// authorization of its requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// the distinguished name of the subject, as `CN=name, O=organization`
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// the dns names of the subject alternative names
+    pub dns_names: Vec<String>,
+    /// the uris of the subject alternative names, spiffe ids among them
+    pub uris: Vec<String>,
+    /// the ip addresses of the subject alternative names
+    pub ip_addresses: Vec<IpAddr>,
+}
+
+impl PeerIdentity {
+    /// the identity of the certificate the client presented, None without
+    /// mutual tls
+    pub fn from_request<T>(request: &Request<T>) -> Option<Self> {
+        let certs = request.peer_certs()?;
+        // the end entity certificate comes first
+        Self::from_der(certs.first()?.get_ref())
+    }
+
+    /// the identity of a der encoded certificate, None if it is invalid
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = parse_x509_certificate(der).ok()?;
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(String::from);
+        let mut identity = Self {
+            subject: cert.subject().to_string(),
+            common_name,
+            dns_names: Vec::new(),
+            uris: Vec::new(),
+            ip_addresses: Vec::new(),
+        };
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) => identity.dns_names.push(name.to_string()),
+                    GeneralName::URI(uri) => identity.uris.push(uri.to_string()),
+                    GeneralName::IPAddress(bytes) => match bytes.len() {
+                        4 => identity
+                            .ip_addresses
+                            .push(IpAddr::from(<[u8; 4]>::try_from(*bytes).unwrap())),
+                        16 => identity
+                            .ip_addresses
+                            .push(IpAddr::from(<[u8; 16]>::try_from(*bytes).unwrap())),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+        Some(identity)
+    }
+}
+
+/// sets the [`PeerIdentity`] of the client as an extension of the requests of
+/// a grpc service, where the handlers and the [`Authorize`] hook read it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityInterceptor;
+
+impl Interceptor for IdentityInterceptor {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(identity) = PeerIdentity::from_request(&req) {
+            req.extensions_mut().insert(identity);
+        }
+        Ok(req)
+    }
+}
+
+/// a change of the registry requested by a client
+#[derive(Debug, Clone, Copy)]
+pub enum Operation<'a> {
+    Register(&'a ServiceInstance),
+    Unregister(&'a ServiceInstanceIdentifier),
+}
+
+/// the authorization hook of the changes of the registry, over grpc and http.
+///
+/// it is given the identity of the client, None when it presented no
+/// certificate, and the change it requested; its error is returned to the
+/// client. set it with `HubConfig::authorizer`, every change is allowed
+/// without one:
+///
+/// ```ignore
+/// #[derive(Debug)]
+/// struct Registrars;
+///
+/// impl Authorize for Registrars {
+///     fn authorize(
+///         &self,
+///         identity: Option<&PeerIdentity>,
+///         _operation: Operation<'_>,
+///     ) -> Result<(), SynapseError> {
+///         match identity {
+///             Some(identity) if identity.uris.contains(&"spiffe://synapse/registrar".into()) => Ok(()),
+///             _ => Err(SynapseError::PermissionDenied("not a registrar".to_string())),
+///         }
+///     }
+/// }
+/// ```
+pub trait Authorize: Debug + Send + Sync {
+    fn authorize(
+        &self,
+        identity: Option<&PeerIdentity>,
+        operation: Operation<'_>,
+    ) -> Result<(), SynapseError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Channel, ClientTlsConfig, Server};
+    use x509_parser::pem::parse_x509_pem;
+
+    use crate::health::{HealthCheckRequest, HealthClient, HealthServer, HealthService};
+
+    use super::*;
+
+    const CLIENT_CERT: &str = include_str!("testdata/client.pem");
+
+    /// records the identity set by the [`IdentityInterceptor`]
+    #[derive(Clone)]
+    struct Peers(Arc<Mutex<Vec<Option<PeerIdentity>>>>);
+
+    impl Interceptor for Peers {
+        fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+            let req = IdentityInterceptor.call(req)?;
+            self.0
+                .lock()
+                .unwrap()
+                .push(req.extensions().get::<PeerIdentity>().cloned());
+            Ok(req)
+        }
+    }
+
+    fn testdata(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/tls/testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        let (_, pem) = parse_x509_pem(CLIENT_CERT.as_bytes()).unwrap();
+        let identity = PeerIdentity::from_der(&pem.contents).unwrap();
+        assert_eq!(identity.subject, "O=synapse, CN=client");
+        assert_eq!(identity.common_name.as_deref(), Some("client"));
+        assert_eq!(identity.dns_names, vec!["registrar.synapse"]);
+        assert_eq!(identity.uris, vec!["spiffe://synapse/registrar"]);
+        assert!(PeerIdentity::from_der(b"not a certificate").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let config = server_tls_config(
+            &testdata("server.pem"),
+            &testdata("server.key"),
+            Some(&testdata("ca.pem")),
+        )
+        .unwrap();
+        let identities = Arc::new(Mutex::new(Vec::new()));
+        let health = HealthServer::with_interceptor(HealthService {}, Peers(identities.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .tls_config(config)
+                .unwrap()
+                .add_service(health)
+                .serve_with_incoming(incoming),
+        );
+
+        let connect = |tls: ClientTlsConfig| async move {
+            let channel = Channel::from_shared(format!("https://localhost:{}", port))
+                .unwrap()
+                .tls_config(tls)
+                .unwrap()
+                .connect()
+                .await?;
+            HealthClient::new(channel)
+                .check(HealthCheckRequest::default())
+                .await
+                .map_err(|status| status.into())
+                .map(|_| ())
+        };
+        let tls = client_tls_config(&testdata("ca.pem"), None, Some("localhost")).unwrap();
+
+        // a client without a certificate is rejected
+        let res: Result<(), Box<dyn std::error::Error>> = connect(tls).await;
+        assert!(res.is_err());
+
+        let identity = (testdata("client.pem"), testdata("client.key"));
+        let tls = client_tls_config(
+            &testdata("ca.pem"),
+            Some((&identity.0, &identity.1)),
+            Some("localhost"),
+        )
+        .unwrap();
+        let res: Result<(), Box<dyn std::error::Error>> = connect(tls).await;
+        assert!(res.is_ok(), "{:?}", res);
+        let identities = identities.lock().unwrap();
+        let identity = identities.last().unwrap().as_ref().unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("client"));
+    }
+}
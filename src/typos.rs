@@ -1,5 +1,8 @@
-use crate::pb::{Scheme, Service, ServiceInstance};
-use crate::service::{QueryRequest, ServiceInstanceIdentifier, SubscribeRequest};
+use crate::pb::{EventKind, Scheme, Service, ServiceEvent, ServiceInstance};
+use crate::service::hub::ServiceKey;
+use crate::service::{
+    QueryRequest, ServiceInstanceIdentifier, SubscribeRequest, SubscriptionFilter,
+};
 use std::fmt::Display;
 
 impl From<ServiceInstance> for Service {
@@ -11,10 +14,18 @@ impl From<ServiceInstance> for Service {
             port: value.port,
             active: value.status,
             scheme: value.scheme,
+            namespace: value.namespace,
         }
     }
 }
 
+impl ServiceInstance {
+    /// the key of the service this instance belongs to
+    pub fn key(&self) -> ServiceKey {
+        ServiceKey::new(self.namespace.clone(), self.name.clone())
+    }
+}
+
 impl From<u8> for Scheme {
     fn from(value: u8) -> Self {
         match value {
@@ -42,20 +53,128 @@ impl Display for Scheme {
     }
 }
 
+impl ServiceEvent {
+    pub fn new(kind: EventKind, instance: ServiceInstance, revision: u64) -> Self {
+        Self {
+            kind: kind as i32,
+            instance: Some(instance),
+            revision,
+            ..Default::default()
+        }
+    }
+
+    /// marks the end of the initial snapshot of a subscription
+    pub fn snapshot_end(revision: u64) -> Self {
+        Self {
+            kind: EventKind::SnapshotEnd as i32,
+            instance: None,
+            revision,
+            ..Default::default()
+        }
+    }
+
+    /// tells a lagged subscriber to drop its view, a fresh snapshot follows
+    pub fn resync(revision: u64) -> Self {
+        Self {
+            kind: EventKind::Resync as i32,
+            instance: None,
+            revision,
+            ..Default::default()
+        }
+    }
+}
+
 impl SubscribeRequest {
     pub fn new(service: String) -> Self {
-        Self { service }
+        Self {
+            service,
+            ..Default::default()
+        }
+    }
+
+    /// subscribe to several services on one stream
+    pub fn for_services<I, S>(services: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            services: services.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// subscribe to every service whose name starts with the prefix
+    pub fn with_prefix(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            ..Default::default()
+        }
+    }
+
+    /// subscribe to every service of the namespace
+    pub fn catalog() -> Self {
+        Self {
+            all: true,
+            ..Default::default()
+        }
+    }
+
+    /// only receive the instances matching the filter
+    pub fn with_filter(mut self, filter: SubscriptionFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
     }
 }
 
 impl QueryRequest {
     pub fn new(name: String) -> Self {
-        Self { name }
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    /// query the service in every namespace instead of a single one
+    pub fn across_namespaces(mut self) -> Self {
+        self.all_namespaces = true;
+        self
     }
 }
 
 impl ServiceInstanceIdentifier {
     pub fn new(name: String, id: String) -> Self {
-        Self { id, name }
+        Self {
+            id,
+            name,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+}
+
+impl From<&str> for SubscribeRequest {
+    fn from(value: &str) -> Self {
+        Self::new(value.to_string())
+    }
+}
+
+impl From<String> for SubscribeRequest {
+    fn from(value: String) -> Self {
+        Self::new(value)
     }
 }
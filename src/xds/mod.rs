@@ -0,0 +1,520 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use envoy_types::pb::envoy::config::cluster::v3::cluster::{
+    ClusterDiscoveryType, DiscoveryType, EdsClusterConfig,
+};
+use envoy_types::pb::envoy::config::cluster::v3::Cluster;
+use envoy_types::pb::envoy::config::core::v3::config_source::ConfigSourceSpecifier;
+use envoy_types::pb::envoy::config::core::v3::socket_address::PortSpecifier;
+use envoy_types::pb::envoy::config::core::v3::{
+    address, Address, AggregatedConfigSource, ApiVersion, ConfigSource, HealthStatus, Node,
+    SocketAddress,
+};
+use envoy_types::pb::envoy::config::endpoint::v3::lb_endpoint::HostIdentifier;
+use envoy_types::pb::envoy::config::endpoint::v3::{
+    ClusterLoadAssignment, Endpoint, LbEndpoint, LocalityLbEndpoints,
+};
+use envoy_types::pb::envoy::service::discovery::v3::aggregated_discovery_service_server::AggregatedDiscoveryService;
+use envoy_types::pb::envoy::service::discovery::v3::{
+    DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
+};
+use envoy_types::pb::google::protobuf::value::Kind;
+use envoy_types::pb::google::protobuf::{Any, Duration, UInt32Value};
+use futures::{Stream, StreamExt};
+use prost::Message;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, warn};
+
+pub use envoy_types::pb::envoy::service::discovery::v3::aggregated_discovery_service_server::AggregatedDiscoveryServiceServer;
+
+use crate::service::client::{MAX_WEIGHT, WEIGHT_METADATA};
+use crate::service::hub::{Hub, Namespace, ServiceKey, DEFAULT_NAMESPACE};
+use crate::service::{ServiceEvent, ServiceRegistry, ServiceStatus, SubscribeRequest};
+
+pub const CLUSTER_TYPE: &str = "type.googleapis.com/envoy.config.cluster.v3.Cluster";
+pub const ENDPOINT_TYPE: &str =
+    "type.googleapis.com/envoy.config.endpoint.v3.ClusterLoadAssignment";
+
+/// the metadata of the envoy node holding the namespace it is served from
+pub const NAMESPACE_METADATA: &str = "namespace";
+
+/// the timeout envoy connects to the endpoints of a cluster with
+pub const CONNECT_TIMEOUT_SECS: i64 = 5;
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<ServiceEvent, Status>> + Send>>;
+
+/// the aggregated discovery service of envoy, serving a cluster per service
+/// (CDS) and the instances which are up as its endpoints (EDS)
+#[derive(Debug, Clone)]
+pub struct XdsServer {
+    hub: Hub,
+}
+
+impl XdsServer {
+    pub fn new(hub: Hub) -> Self {
+        Self { hub }
+    }
+}
+
+/// the resources of a type requested by envoy
+#[derive(Debug, Default)]
+struct Watch {
+    /// the requested resources, every resource when empty
+    names: Vec<String>,
+    /// the nonce of the last response, envoy answers with it
+    nonce: String,
+    /// the resources of the last response
+    resources: Option<Vec<Any>>,
+}
+
+/// the state of an ads stream, served from one namespace
+struct AdsStream {
+    hub: Hub,
+    namespace: Namespace,
+    watches: HashMap<String, Watch>,
+    nonce: u64,
+}
+
+impl AdsStream {
+    fn new(hub: Hub, namespace: Namespace) -> Self {
+        Self {
+            hub,
+            namespace,
+            watches: HashMap::new(),
+            nonce: 0,
+        }
+    }
+
+    fn handle_request(&mut self, req: DiscoveryRequest) -> Option<DiscoveryResponse> {
+        if req.type_url != CLUSTER_TYPE && req.type_url != ENDPOINT_TYPE {
+            warn!("xds resource type not served: {}", req.type_url);
+            return None;
+        }
+        let watch = self.watches.entry(req.type_url.clone()).or_default();
+        let mut names = req.resource_names;
+        names.sort();
+        if req.response_nonce.is_empty() {
+            // a new subscription
+            watch.names = names;
+            watch.resources = None;
+            return self.respond(&req.type_url);
+        }
+        if req.response_nonce != watch.nonce {
+            debug!("stale xds nonce {} of {}", req.response_nonce, req.type_url);
+            return None;
+        }
+        // envoy keeps the previous version, it is not sent again until the
+        // resources change
+        if let Some(error) = req.error_detail {
+            warn!(
+                "xds version {} of {} rejected: {}",
+                req.version_info, req.type_url, error.message
+            );
+        }
+        if watch.names != names {
+            watch.names = names;
+            return self.respond(&req.type_url);
+        }
+        None
+    }
+
+    /// the responses of the watches whose resources changed
+    fn refresh(&mut self) -> Vec<DiscoveryResponse> {
+        let type_urls: Vec<_> = self.watches.keys().cloned().collect();
+        type_urls
+            .iter()
+            .filter_map(|type_url| self.respond(type_url))
+            .collect()
+    }
+
+    /// the current resources of a watch, unless they were already sent
+    fn respond(&mut self, type_url: &str) -> Option<DiscoveryResponse> {
+        let watch = self.watches.get(type_url)?;
+        let resources = if type_url == CLUSTER_TYPE {
+            self.clusters(&watch.names)
+        } else {
+            self.assignments(&watch.names)
+        };
+        if watch.resources.as_ref() == Some(&resources) {
+            return None;
+        }
+
+        self.nonce += 1;
+        let nonce = self.nonce.to_string();
+        let version_info = self.hub.revision().to_string();
+        let watch = self.watches.get_mut(type_url)?;
+        watch.nonce.clone_from(&nonce);
+        watch.resources = Some(resources.clone());
+        Some(DiscoveryResponse {
+            version_info,
+            resources,
+            type_url: type_url.to_string(),
+            nonce,
+            ..Default::default()
+        })
+    }
+
+    fn clusters(&self, names: &[String]) -> Vec<Any> {
+        self.hub
+            .catalog(Some(&self.namespace))
+            .into_iter()
+            .filter(|(key, _)| names.is_empty() || names.contains(&key.name))
+            .map(|(key, _)| {
+                let cluster = Cluster {
+                    name: key.name,
+                    cluster_discovery_type: Some(ClusterDiscoveryType::Type(
+                        DiscoveryType::Eds as i32,
+                    )),
+                    connect_timeout: Some(Duration {
+                        seconds: CONNECT_TIMEOUT_SECS,
+                        nanos: 0,
+                    }),
+                    eds_cluster_config: Some(EdsClusterConfig {
+                        eds_config: Some(ConfigSource {
+                            resource_api_version: ApiVersion::V3 as i32,
+                            config_source_specifier: Some(ConfigSourceSpecifier::Ads(
+                                AggregatedConfigSource {},
+                            )),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                any(CLUSTER_TYPE, &cluster)
+            })
+            .collect()
+    }
+
+    /// the endpoints of the requested clusters, instances whose address is not
+    /// an ip address or whose weight is 0 are left out. envoy rejects the
+    /// weights of a locality adding up to more than [`MAX_WEIGHT`], they are
+    /// capped at their share of it
+    fn assignments(&self, names: &[String]) -> Vec<Any> {
+        let names = if names.is_empty() {
+            let catalog = self.hub.catalog(Some(&self.namespace));
+            catalog.into_iter().map(|(key, _)| key.name).collect()
+        } else {
+            names.to_vec()
+        };
+        names
+            .into_iter()
+            .map(|name| {
+                let mut instances = self.hub.instances(&ServiceKey::new(&self.namespace, &name));
+                instances.sort_by(|a, b| a.id.cmp(&b.id));
+                let instances: Vec<_> = instances
+                    .into_iter()
+                    .filter(|instance| instance.status() == ServiceStatus::Up)
+                    .filter(|instance| instance.address.parse::<IpAddr>().is_ok())
+                    .map(|instance| {
+                        let weight = instance
+                            .metadata
+                            .get(WEIGHT_METADATA)
+                            .and_then(|weight| weight.parse::<u64>().ok());
+                        (instance, weight)
+                    })
+                    .filter(|(_, weight)| *weight != Some(0))
+                    .collect();
+                let max_weight = MAX_WEIGHT / instances.len().max(1) as u64;
+                let lb_endpoints = instances
+                    .into_iter()
+                    .map(|(instance, weight)| {
+                        let address = SocketAddress {
+                            address: instance.address.clone(),
+                            port_specifier: Some(PortSpecifier::PortValue(instance.port as u32)),
+                            ..Default::default()
+                        };
+                        let weight = weight.map(|weight| UInt32Value {
+                            value: weight.min(max_weight) as u32,
+                        });
+                        LbEndpoint {
+                            host_identifier: Some(HostIdentifier::Endpoint(Endpoint {
+                                address: Some(Address {
+                                    address: Some(address::Address::SocketAddress(address)),
+                                }),
+                                ..Default::default()
+                            })),
+                            health_status: HealthStatus::Healthy as i32,
+                            load_balancing_weight: weight,
+                            ..Default::default()
+                        }
+                    })
+                    .collect();
+                let assignment = ClusterLoadAssignment {
+                    cluster_name: name,
+                    endpoints: vec![LocalityLbEndpoints {
+                        lb_endpoints,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                };
+                any(ENDPOINT_TYPE, &assignment)
+            })
+            .collect()
+    }
+}
+
+fn any(type_url: &str, message: &impl Message) -> Any {
+    Any {
+        type_url: type_url.to_string(),
+        value: message.encode_to_vec(),
+    }
+}
+
+/// the namespace in the metadata of the node, the default one when missing
+fn node_namespace(node: Option<&Node>) -> Namespace {
+    node.and_then(|node| node.metadata.as_ref())
+        .and_then(|metadata| metadata.fields.get(NAMESPACE_METADATA))
+        .and_then(|value| match value.kind {
+            Some(Kind::StringValue(ref namespace)) => Some(namespace.clone()),
+            _ => None,
+        })
+        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string())
+}
+
+/// the changes of the services of the namespace
+async fn changes(hub: &Hub, namespace: &str) -> Option<EventStream> {
+    let req = SubscribeRequest::catalog().with_namespace(namespace);
+    let res = hub.subscribe(Request::new(req)).await.ok()?;
+    Some(res.into_inner())
+}
+
+#[async_trait]
+impl AggregatedDiscoveryService for XdsServer {
+    type StreamAggregatedResourcesStream =
+        Pin<Box<dyn Stream<Item = Result<DiscoveryResponse, Status>> + Send>>;
+
+    async fn stream_aggregated_resources(
+        &self,
+        request: Request<Streaming<DiscoveryRequest>>,
+    ) -> Result<Response<Self::StreamAggregatedResourcesStream>, Status> {
+        let mut requests = request.into_inner();
+        let (tx, rx) = mpsc::channel(16);
+        let hub = self.hub.clone();
+
+        tokio::spawn(async move {
+            let Ok(Some(first)) = requests.message().await else {
+                return;
+            };
+            let namespace = node_namespace(first.node.as_ref());
+            debug!("xds stream of namespace {}", namespace);
+            let mut stream = AdsStream::new(hub.clone(), namespace.clone());
+            let Some(mut events) = changes(&hub, &namespace).await else {
+                return;
+            };
+            let mut pending = Some(first);
+            loop {
+                let responses = match pending.take() {
+                    Some(req) => stream.handle_request(req).into_iter().collect(),
+                    None => tokio::select! {
+                        _ = tx.closed() => return,
+                        req = requests.message() => match req {
+                            Ok(Some(req)) => stream.handle_request(req).into_iter().collect(),
+                            // envoy closed the stream
+                            _ => return,
+                        },
+                        event = events.next() => {
+                            if !matches!(event, Some(Ok(_))) {
+                                // the subscription failed, the resources are
+                                // refreshed from the new one
+                                let Some(resubscribed) = changes(&hub, &namespace).await else {
+                                    return;
+                                };
+                                events = resubscribed;
+                            }
+                            stream.refresh()
+                        }
+                    },
+                };
+                for response in responses {
+                    if tx.send(Ok(response)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    type DeltaAggregatedResourcesStream =
+        Pin<Box<dyn Stream<Item = Result<DeltaDiscoveryResponse, Status>> + Send>>;
+
+    async fn delta_aggregated_resources(
+        &self,
+        _request: Request<Streaming<DeltaDiscoveryRequest>>,
+    ) -> Result<Response<Self::DeltaAggregatedResourcesStream>, Status> {
+        Err(Status::unimplemented("incremental xds is not supported"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use envoy_types::pb::google::rpc::Status as RpcStatus;
+
+    use crate::service::ServiceInstance;
+
+    use super::*;
+
+    fn request(type_url: &str, version: &str, nonce: &str) -> DiscoveryRequest {
+        DiscoveryRequest {
+            type_url: type_url.to_string(),
+            version_info: version.to_string(),
+            response_nonce: nonce.to_string(),
+            ..Default::default()
+        }
+    }
+
+    async fn register(hub: &Hub, id: &str, address: &str, status: ServiceStatus) {
+        register_weighted(hub, id, address, status, "3").await;
+    }
+
+    async fn register_weighted(
+        hub: &Hub,
+        id: &str,
+        address: &str,
+        status: ServiceStatus,
+        weight: &str,
+    ) {
+        let mut instance = ServiceInstance {
+            id: id.to_string(),
+            name: "api".to_string(),
+            address: address.to_string(),
+            port: 8080,
+            status: status as i32,
+            ..Default::default()
+        };
+        instance
+            .metadata
+            .insert(WEIGHT_METADATA.to_string(), weight.to_string());
+        hub.register_service(Request::new(instance)).await.unwrap();
+    }
+
+    fn endpoints(res: &DiscoveryResponse) -> Vec<(String, u32)> {
+        let assignment = ClusterLoadAssignment::decode(&res.resources[0].value[..]).unwrap();
+        assert_eq!(assignment.cluster_name, "api");
+        assignment.endpoints[0]
+            .lb_endpoints
+            .iter()
+            .map(|lb_endpoint| {
+                let Some(HostIdentifier::Endpoint(endpoint)) = &lb_endpoint.host_identifier else {
+                    panic!("no endpoint");
+                };
+                let Some(address::Address::SocketAddress(address)) =
+                    endpoint.address.as_ref().and_then(|a| a.address.clone())
+                else {
+                    panic!("no socket address");
+                };
+                let weight = lb_endpoint.load_balancing_weight.clone().unwrap().value;
+                (address.address, weight)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_clusters() {
+        let hub = Hub::new();
+        register(&hub, "1", "127.0.0.1", ServiceStatus::Up).await;
+        let mut stream = AdsStream::new(hub, DEFAULT_NAMESPACE.to_string());
+
+        let res = stream
+            .handle_request(request(CLUSTER_TYPE, "", ""))
+            .unwrap();
+        assert_eq!(res.type_url, CLUSTER_TYPE);
+        let cluster = Cluster::decode(&res.resources[0].value[..]).unwrap();
+        assert_eq!(cluster.name, "api");
+        assert_eq!(
+            cluster.cluster_discovery_type,
+            Some(ClusterDiscoveryType::Type(DiscoveryType::Eds as i32))
+        );
+
+        // acked, nothing changed
+        let ack = request(CLUSTER_TYPE, &res.version_info, &res.nonce);
+        assert!(stream.handle_request(ack).is_none());
+        assert!(stream.refresh().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let hub = Hub::new();
+        register(&hub, "1", "127.0.0.1", ServiceStatus::Up).await;
+        register(&hub, "2", "127.0.0.2", ServiceStatus::Down).await;
+        register(&hub, "3", "api.local", ServiceStatus::Up).await;
+        let mut stream = AdsStream::new(hub.clone(), DEFAULT_NAMESPACE.to_string());
+
+        let mut req = request(ENDPOINT_TYPE, "", "");
+        req.resource_names = vec!["api".to_string()];
+        let res = stream.handle_request(req.clone()).unwrap();
+        assert_eq!(endpoints(&res), vec![("127.0.0.1".to_string(), 3)]);
+
+        // a stale nonce is ignored
+        req.response_nonce = "0".to_string();
+        assert!(stream.handle_request(req.clone()).is_none());
+
+        // rejected, it is not sent again
+        req.version_info = res.version_info.clone();
+        req.response_nonce = res.nonce.clone();
+        req.error_detail = Some(RpcStatus {
+            message: "invalid".to_string(),
+            ..Default::default()
+        });
+        assert!(stream.handle_request(req).is_none());
+        assert!(stream.refresh().is_empty());
+
+        register(&hub, "2", "127.0.0.2", ServiceStatus::Up).await;
+        let refreshed = stream.refresh();
+        assert_eq!(refreshed.len(), 1);
+        assert_ne!(refreshed[0].nonce, res.nonce);
+        assert_ne!(refreshed[0].version_info, res.version_info);
+        assert_eq!(
+            endpoints(&refreshed[0]),
+            vec![("127.0.0.1".to_string(), 3), ("127.0.0.2".to_string(), 3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_weights() {
+        let hub = Hub::new();
+        let max = u64::MAX.to_string();
+        register_weighted(&hub, "1", "127.0.0.1", ServiceStatus::Up, "0").await;
+        register_weighted(&hub, "2", "127.0.0.2", ServiceStatus::Up, &max).await;
+        register_weighted(&hub, "3", "127.0.0.3", ServiceStatus::Up, &max).await;
+        let mut stream = AdsStream::new(hub, DEFAULT_NAMESPACE.to_string());
+
+        let res = stream
+            .handle_request(request(ENDPOINT_TYPE, "", ""))
+            .unwrap();
+        let weights = endpoints(&res);
+        let share = (MAX_WEIGHT / 2) as u32;
+        assert_eq!(
+            weights,
+            vec![
+                ("127.0.0.2".to_string(), share),
+                ("127.0.0.3".to_string(), share)
+            ]
+        );
+        let total: u64 = weights.iter().map(|(_, weight)| *weight as u64).sum();
+        assert!(total <= MAX_WEIGHT);
+    }
+
+    #[test]
+    fn test_node_namespace() {
+        assert_eq!(node_namespace(None), DEFAULT_NAMESPACE);
+        let mut metadata = envoy_types::pb::google::protobuf::Struct::default();
+        metadata.fields.insert(
+            NAMESPACE_METADATA.to_string(),
+            envoy_types::pb::google::protobuf::Value {
+                kind: Some(Kind::StringValue("team-a".to_string())),
+            },
+        );
+        let node = Node {
+            metadata: Some(metadata),
+            ..Default::default()
+        };
+        assert_eq!(node_namespace(Some(&node)), "team-a");
+    }
+}